@@ -13,3 +13,40 @@ fn bench_broadcast_encode(b: &mut test::Bencher) {
     });
 }
 
+fn mask_bytewise(masking_key: u32, bytes: &mut [u8]) {
+    let key = [(masking_key >> 24) as u8,
+               (masking_key >> 16) as u8,
+               (masking_key >> 8) as u8,
+               masking_key as u8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+#[bench]
+fn bench_mask_bytewise_64k(b: &mut test::Bencher) {
+    let mut payload = vec![0x55u8; 65536];
+    b.bytes = payload.len() as u64;
+    b.iter(|| mask_bytewise(0x11223344, &mut payload));
+}
+
+#[bench]
+fn bench_mask_in_place_64k(b: &mut test::Bencher) {
+    let mut payload = vec![0x55u8; 65536];
+    b.bytes = payload.len() as u64;
+    b.iter(|| websocket::mask_in_place(0x11223344, &mut payload));
+}
+
+#[bench]
+fn bench_mask_in_place_unaligned(b: &mut test::Bencher) {
+    let mut payload = vec![0x55u8; 65536 + 3];
+    b.bytes = 65536;
+    b.iter(|| websocket::mask_in_place(0x11223344, &mut payload[3..]));
+}
+
+#[bench]
+fn bench_mask_in_place_small(b: &mut test::Bencher) {
+    let mut payload = vec![0x55u8; 45];
+    b.bytes = payload.len() as u64;
+    b.iter(|| websocket::mask_in_place(0x11223344, &mut payload));
+}
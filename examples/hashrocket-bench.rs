@@ -9,51 +9,43 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::io::{Error, ErrorKind};
+use std::time::Duration;
 
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::Core;
-use tokio_io::AsyncRead;
+use tokio_io::codec::Framed;
 
-use futures::{Future, Stream, Sink};
-use futures::sync::mpsc;
+use futures::{Future, Stream};
 
-use websocket::{Request, WebSocketCodec, new_text_frame, Opcode, Frame};
+use websocket::{Request, CloseCode, Connection, KeepAlive, Message, MessageCodec, PingPong,
+                accept, new_close_frame, new_text_frame, Frame};
 
 const NULL_PAYLOAD: &'static Value = &Value::Null;
 
-enum Message {
+enum Reply {
     Echo(Frame),
     Broadcast(Frame, Frame),
     None(),
 }
 
-fn process_frame(frame: Frame) -> Message {
-    if frame.header.opcode == Opcode::Close {
-        return Message::Echo(frame);
-    }
-    if frame.header.opcode != Opcode::Text {
-        return Message::None();
-    }
-    // TODO send back pongs
-
-    let payload = frame.payload_string().unwrap();
-    if let Ok(Value::Object(obj)) = serde_json::from_str::<Value>(&payload) {
+fn process_text(text: &str) -> Reply {
+    if let Ok(Value::Object(obj)) = serde_json::from_str::<Value>(text) {
         if let Some(&Value::String(ref s)) = obj.get("type") {
             if s == "echo" {
-                return Message::Echo(frame);
+                return Reply::Echo(new_text_frame(text, None));
             }
             if s == "broadcast" {
                 let msg = format!(r#"{{"type":"broadcastResult","payload":{}}}"#, obj.get("payload").unwrap_or(NULL_PAYLOAD));
-                return Message::Broadcast(frame, new_text_frame(&msg, None));
+                return Reply::Broadcast(new_text_frame(text, None), new_text_frame(&msg, None));
             }
         }
     }
-    Message::None()
+    Reply::None()
 }
 
 fn main() {
-    // Set up using skeleton of chat example, use encode and decode directly
+    // Set up using skeleton of chat example, pushing frames instead of
+    // answering each request
     let addr = "0.0.0.0:8084".parse().unwrap();
 
     let mut core = Core::new().unwrap();
@@ -64,55 +56,61 @@ fn main() {
     let connections = Rc::new(RefCell::new(HashMap::new()));
 
     let srv = socket.incoming().for_each(move |(conn, addr)| {
-        let (sink, stream) = conn.framed(WebSocketCodec::new()).split();
-        let (tx, rx) = mpsc::unbounded();
-
-        connections.borrow_mut().insert(addr, tx);
-
-        let connections_inner = connections.clone();
-        let reader = stream.for_each(move |req| {
-            let mut conns = connections_inner.borrow_mut();
-            match req {
-                Request::Frame(frame) => {
-                    match process_frame(frame) {
-                        Message::None() => {},
-                        Message::Echo(frame) => {
-                            if frame.header.opcode == Opcode::Close {
-                                conns.remove(&addr);
-                                return Err(Error::new(ErrorKind::Other, "close requested"))
-                            }
-                            let tx = conns.get_mut(&addr).unwrap();
-                            let masked_frame = new_text_frame(&frame.payload_string().unwrap(), None);
-                            mpsc::UnboundedSender::send(&mut std::borrow::BorrowMut::borrow_mut(tx), masked_frame).unwrap();
-                        },
-                        Message::Broadcast(broadcast_frame, echo_frame) => {
-                            let masked_frame = new_text_frame(&broadcast_frame.payload_string().unwrap(), None);
-                            for (&t_addr, tx) in conns.iter_mut() {
-                                mpsc::UnboundedSender::send(&mut std::borrow::BorrowMut::borrow_mut(tx), masked_frame.clone()).unwrap();
-                                if addr == t_addr {
-                                    mpsc::UnboundedSender::send(&mut std::borrow::BorrowMut::borrow_mut(tx), echo_frame.clone()).unwrap();
-                                }
-                            }
-                        },
-                    }
-                },
-                Request::Open() => {
-                    let tx = conns.get_mut(&addr).unwrap();
-                    mpsc::UnboundedSender::send(&mut std::borrow::BorrowMut::borrow_mut(tx), new_text_frame("this message is dropped", None)).unwrap();
-                }
-            }
-            Ok(())
-        });
+        let handle_inner = handle.clone();
         let connections = connections.clone();
-        let writer = rx.map_err(|_| Error::new(ErrorKind::Other, "receiver error")).fold(sink, |sink, msg| {
-            sink.send(msg)
-        });
-        let reader = reader.map_err(|_| Error::new(ErrorKind::Other, "transmitter error"));
-        let conn = reader.map(|_| ()).select(writer.map(|_| ()));
-        handle.spawn(conn.then(move |_| {
-            connections.borrow_mut().remove(&addr);
-            Ok(())
-        }));
+        let session = accept(conn)
+            .and_then(move |(_, transport)| {
+                // Reassemble fragmented messages, text arrives already
+                // validated as a String
+                let (parts, codec) = transport.into_parts_and_codec();
+                let transport = Framed::from_parts(parts, MessageCodec::new(codec));
+                // Ping idle connections well within the load balancer's 60s idle limit
+                let transport = try!(KeepAlive::new(transport,
+                                                    Duration::from_secs(30),
+                                                    Duration::from_secs(10),
+                                                    &handle_inner));
+                Ok(Connection::new(PingPong::new(transport)))
+            })
+            .and_then(move |conn| {
+                connections.borrow_mut().insert(addr, conn.sender());
+
+                let connections_inner = connections.clone();
+                conn.for_each(move |req| {
+                        let conns = connections_inner.borrow();
+                        match req {
+                            Request::Message(Message::Text(text)) => {
+                                match process_text(&text) {
+                                    Reply::None() => {},
+                                    Reply::Echo(frame) => {
+                                        try!(conns[&addr].push(frame));
+                                    },
+                                    Reply::Broadcast(frame, echo_frame) => {
+                                        for (&t_addr, sender) in conns.iter() {
+                                            // Connections that went away are removed below
+                                            let _ = sender.push(frame.clone());
+                                            if addr == t_addr {
+                                                try!(sender.push(echo_frame.clone()));
+                                            }
+                                        }
+                                    },
+                                }
+                            },
+                            Request::Close(_) => {
+                                // The client drops the connection once our Close
+                                // arrives, which ends the stream
+                                try!(conns[&addr].push(new_close_frame(CloseCode::Normal, "", None)));
+                            },
+                            Request::Message(Message::Binary(_)) | Request::Frame(_) | Request::Open(_) |
+                            Request::Rejected(_) | Request::Http(_) => {},
+                        }
+                        Ok(())
+                    })
+                    .then(move |_| {
+                        connections.borrow_mut().remove(&addr);
+                        Ok(())
+                    })
+            });
+        handle.spawn(session.map_err(|_| ()));
         Ok(())
     });
 
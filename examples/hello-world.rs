@@ -6,7 +6,7 @@ extern crate websocket;
 use std::io;
 
 use futures::future;
-use websocket::{Request, Response, WebSocket, new_text_frame};
+use websocket::{CloseCode, Request, Response, WebSocket, new_close_frame, new_text_frame};
 use tokio_proto::TcpServer;
 use tokio_service::Service;
 
@@ -20,12 +20,15 @@ impl Service for HelloWorld {
     fn call(&self, request: Request) -> Self::Future {
         println!("{:?}", request);
         match request {
-            Request::Open() => {
+            Request::Open(_) => {
                 // This gets dropped, should signal that
                 let res = new_text_frame("Hello world!", None);
                 future::ok(res)
             },
-            Request::Frame(_) => {
+            Request::Close(_) => {
+                future::ok(new_close_frame(CloseCode::Normal, "", None))
+            },
+            Request::Frame(_) | Request::Message(_) | Request::Rejected(_) | Request::Http(_) => {
                 let res = new_text_frame("Hello world!", None);
                 future::ok(res)
             },
@@ -36,6 +39,6 @@ impl Service for HelloWorld {
 
 fn main() {
     let addr = "0.0.0.0:8084".parse().unwrap();
-    TcpServer::new(WebSocket, addr)
+    TcpServer::new(WebSocket::new(), addr)
         .serve(|| Ok(HelloWorld));
 }
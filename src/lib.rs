@@ -1,5 +1,9 @@
 extern crate base64;
 extern crate bytes;
+extern crate flate2;
+#[macro_use]
+extern crate futures;
+extern crate httparse;
 extern crate ring;
 extern crate tokio_core;
 extern crate tokio_io;
@@ -7,53 +11,830 @@ extern crate tokio_minihttp;
 extern crate tokio_proto;
 extern crate tokio_service;
 
+use std::collections::VecDeque;
 use std::io;
+use std::net::SocketAddr;
 use bytes::BytesMut;
 use tokio_io::codec::{Encoder, Decoder, Framed};
+use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_proto::pipeline::ServerProto;
 use tokio_minihttp::HttpCodec;
 
+use ws_extension::Extensions;
+use ws_request::{IncomingFrames, outgoing_frames};
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+
+    use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream, future, task};
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// An in-memory socket. Reads past the end of `input` would block.
+    pub struct MockIo {
+        input: io::Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for MockIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match try!(self.input.read(buf)) {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for MockIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MockIo {
+        /// More input, as if the peer sent it after everything so far.
+        pub fn push_input(&mut self, data: &[u8]) {
+            self.input.get_mut().extend_from_slice(data);
+        }
+    }
+
+    impl AsyncRead for MockIo {}
+
+    impl AsyncWrite for MockIo {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    pub fn mock_io(input: &[u8]) -> (MockIo, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let io = MockIo {
+            input: io::Cursor::new(input.to_vec()),
+            output: output.clone(),
+        };
+        (io, output)
+    }
+
+    /// An in-memory transport of requests and responses, for what wraps one.
+    pub struct MockTransport {
+        pub incoming: VecDeque<Request>,
+        pub outgoing: Vec<Response>,
+        // Keep the stream open once `incoming` is drained
+        pub idle: bool,
+        // Answer pings like a live peer would
+        pub echo_pings: bool,
+        // Take no frames, like a peer that stopped reading
+        pub full: bool,
+    }
+
+    impl MockTransport {
+        pub fn new(incoming: Vec<Frame>) -> MockTransport {
+            MockTransport {
+                incoming: incoming.into_iter().map(Request::Frame).collect(),
+                outgoing: Vec::new(),
+                idle: false,
+                echo_pings: false,
+                full: false,
+            }
+        }
+
+        pub fn idle(echo_pings: bool) -> MockTransport {
+            let mut transport = MockTransport::new(Vec::new());
+            transport.idle = true;
+            transport.echo_pings = echo_pings;
+            transport
+        }
+    }
+
+    impl Stream for MockTransport {
+        type Item = Request;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+            match self.incoming.pop_front() {
+                None if self.idle => Ok(Async::NotReady),
+                req => Ok(Async::Ready(req)),
+            }
+        }
+    }
+
+    impl Sink for MockTransport {
+        type SinkItem = Response;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Response) -> StartSend<Response, io::Error> {
+            if self.full {
+                return Ok(AsyncSink::NotReady(item));
+            }
+            if self.echo_pings && item.header.opcode == Opcode::Ping {
+                self.incoming.push_back(Request::Frame(new_pong_frame(&item.payload, None)));
+                task::current().notify();
+            }
+            self.outgoing.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn upgrade_request(extra_headers: &str) -> BytesMut {
+        let req = format!("GET /ws/chat/42?token=abc HTTP/1.1\r\n\
+                           Host: server.example.com\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\
+                           {}\r\n",
+                          extra_headers);
+        BytesMut::from(req.as_bytes())
+    }
+
+    #[test]
+    fn open_carries_handshake() {
+        let mut config = Config::new();
+        config.protocols(&["v2.json"]);
+        let mut codec = WebSocketCodec::with_config(config);
+        codec.peer_addr("127.0.0.1:4000".parse().unwrap());
+        let mut buf = upgrade_request("Authorization: Bearer abc\r\n\
+                                       Sec-WebSocket-Protocol: mqtt, v2.json\r\n");
+        let handshake = match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(handshake))) => handshake,
+            e => panic!("handshake failed: {:?}", e),
+        };
+        assert_eq!(handshake.method, "GET");
+        assert_eq!(handshake.path(), "/ws/chat/42");
+        assert_eq!(handshake.query(), Some("token=abc"));
+        assert_eq!(handshake.header("authorization"), Some(&b"Bearer abc"[..]));
+        assert_eq!(handshake.peer_addr, Some("127.0.0.1:4000".parse().unwrap()));
+        assert_eq!(handshake.protocol, Some("v2.json".to_string()));
+
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("dropped", None), &mut buf).unwrap();
+        let res = String::from_utf8(buf.to_vec()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 101"));
+        assert!(res.contains("\r\nSec-WebSocket-Protocol: v2.json\r\n"));
+    }
+
+    #[test]
+    fn codecs_are_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<WebSocketCodec>();
+        assert_send::<WebSocketClientCodec>();
+        assert_send::<Framed<TcpStream, WebSocketCodec>>();
+    }
+
+    #[test]
+    fn bind_transport_any_stream() {
+        let (io, _) = mock_io(&upgrade_request(""));
+        let transport = WebSocket::new().bind_transport(io).unwrap();
+        match transport.into_future().wait() {
+            Ok((Some(Request::Open(handshake)), _)) => assert_eq!(handshake.peer_addr, None),
+            _ => panic!("handshake failed"),
+        }
+    }
+
+    #[test]
+    fn bind_transport_sets_peer_addr() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let client = TcpStream::connect(&listener.local_addr().unwrap(), &handle)
+            .and_then(|conn| tokio_io::io::write_all(conn, upgrade_request("")));
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(conn, _)| {
+                let (conn, _) = conn.unwrap();
+                let transport = WebSocket::new().with_peer_addr().bind_transport(conn).unwrap();
+                transport.into_future().map_err(|(e, _)| e)
+            });
+        let ((conn, _), (req, _)) = core.run(client.join(server)).unwrap();
+        match req {
+            Some(Request::Open(handshake)) => {
+                assert_eq!(handshake.peer_addr, Some(conn.local_addr().unwrap()))
+            }
+            _ => panic!("handshake failed"),
+        }
+    }
+
+    #[test]
+    fn text_messages_validated_per_frame() {
+        let mut codec = MessageCodec::new(WebSocketCodec::new());
+        open(codec.get_mut());
+        // "é" split between two masked frames
+        let mut buf = BytesMut::from(vec![0x01, 0x81, 0, 0, 0, 0, 0xc3, 0x80, 0x81, 0, 0, 0, 0, 0xa9]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Message(Message::Text(ref text)))) => assert_eq!(text, "é"),
+            e => panic!("expected text: {:?}", e),
+        }
+        // Rejected with the frame that makes it invalid, before the message
+        // is complete
+        let mut buf = BytesMut::from(vec![0x01, 0x81, 0, 0, 0, 0, 0xff]);
+        match codec.decode(&mut buf) {
+            Err(ref e) => {
+                match WebSocketError::from_io(e) {
+                    Some(&WebSocketError::InvalidUtf8) => {}
+                    e => panic!("unexpected error: {:?}", e),
+                }
+            }
+            Ok(req) => panic!("expected an error: {:?}", req),
+        }
+    }
+
+    #[test]
+    fn failed_codec_stays_failed() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        // Text that starts invalid, then a continuation that is fine alone
+        let mut buf = BytesMut::from(vec![0x01, 0x81, 0, 0, 0, 0, 0xff]);
+        assert!(codec.decode(&mut buf).is_err());
+        buf.extend_from_slice(&[0x80, 0x81, 0, 0, 0, 0, b'a']);
+        for _ in 0..2 {
+            match codec.decode(&mut buf) {
+                Err(ref e) => {
+                    match WebSocketError::from_io(e) {
+                        Some(&WebSocketError::InvalidUtf8) => {}
+                        e => panic!("unexpected error: {:?}", e),
+                    }
+                }
+                Ok(req) => panic!("decoded after failing: {:?}", req),
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_transport_ends() {
+        let (io, output) = mock_io(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\n\r\n");
+        let transport = io.framed(WebSocketCodec::new());
+        let transport = match transport.into_future().wait() {
+            Ok((Some(Request::Rejected(_)), transport)) => transport,
+            _ => panic!("expected rejection"),
+        };
+        let mut transport = transport.send(new_text_frame("dropped", None)).wait().unwrap();
+        let res = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 400"));
+
+        // The peer keeps talking instead of closing
+        transport.get_mut().push_input(b"GET /chat HTTP/1.1\r\n");
+        let poll = future::poll_fn(|| Ok::<_, ()>(Async::Ready(transport.poll())));
+        match poll.wait().unwrap() {
+            Err(ref e) => {
+                match WebSocketError::from_io(e) {
+                    Some(&WebSocketError::HandshakeFailed(_)) => {}
+                    e => panic!("unexpected error: {:?}", e),
+                }
+            }
+            Ok(_) => panic!("expected the stream to fail"),
+        }
+    }
+
+    #[test]
+    fn http_fallback() {
+        let mut config = Config::new();
+        config.http_handler(|req| {
+            let mut res = tokio_minihttp::Response::new();
+            res.body(req.path());
+            res
+        });
+        let mut codec = WebSocketCodec::with_config(config);
+        let mut buf = BytesMut::from(&b"GET /health HTTP/1.1\r\nHost: a\r\n\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Http(ref req))) => assert_eq!(req.uri, "/health"),
+            e => panic!("expected http request: {:?}", e),
+        }
+        let mut out = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("dropped", None), &mut out).unwrap();
+        let res = String::from_utf8(out.to_vec()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.ends_with("\r\n\r\n/health"));
+
+        // The connection can still be upgraded afterwards
+        let mut buf = upgrade_request("");
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(_))) => {}
+            e => panic!("handshake failed: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn http_pipelined() {
+        let mut config = Config::new();
+        config.http_handler(|req| {
+            let mut res = tokio_minihttp::Response::new();
+            res.body(req.path());
+            res
+        });
+        let mut codec = WebSocketCodec::with_config(config);
+        let mut buf = BytesMut::from(&b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n\
+                                         GET /b HTTP/1.1\r\nHost: a\r\n\r\n"[..]);
+        buf.extend_from_slice(&upgrade_request(""));
+        // Everything already read is decoded before any answer is written
+        for path in &["/a", "/b"] {
+            match codec.decode(&mut buf) {
+                Ok(Some(Request::Http(ref req))) => assert_eq!(req.uri, *path),
+                e => panic!("expected http request: {:?}", e),
+            }
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(_))) => {}
+            e => panic!("handshake failed: {:?}", e),
+        }
+
+        // The answers are written in order, the 101 last
+        let mut out = BytesMut::with_capacity(0);
+        for _ in 0..3 {
+            codec.encode(new_text_frame("dropped", None), &mut out).unwrap();
+        }
+        let res = String::from_utf8(out.to_vec()).unwrap();
+        let a = res.find("\r\n\r\n/a").unwrap();
+        let b = res.find("\r\n\r\n/b").unwrap();
+        let upgrade = res.find("HTTP/1.1 101").unwrap();
+        assert!(a < b && b < upgrade);
+    }
+
+    #[test]
+    fn http_without_handler() {
+        let mut codec = WebSocketCodec::new();
+        let mut buf = BytesMut::from(&b"GET /health HTTP/1.1\r\nHost: a\r\n\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Rejected(_))) => {}
+            e => panic!("expected rejection: {:?}", e),
+        }
+    }
+
+    fn open(codec: &mut WebSocketCodec) {
+        let mut buf = upgrade_request("");
+        codec.decode(&mut buf).unwrap();
+        codec.encode(new_text_frame("dropped", None), &mut BytesMut::with_capacity(0)).unwrap();
+    }
+
+    #[test]
+    fn unmasked_client_frame() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::from(vec![0x81, 0x84, 1, 2, 3, 4, b'b' ^ 1, b'l' ^ 2, b'u' ^ 3,
+                                          b'b' ^ 4]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(ref frame))) => {
+                assert_eq!(frame.payload_string().unwrap(), "blub")
+            }
+            e => panic!("decode failed: {:?}", e),
+        }
+        let mut buf = BytesMut::from(vec![0x81, 0x04, b'b', b'l', b'u', b'b']);
+        let err = codec.decode(&mut buf).unwrap_err();
+        let err = WebSocketError::from_io(&err).unwrap();
+        assert_eq!(err.close_code(), CloseCode::ProtocolError);
+    }
+
+    #[test]
+    fn invalid_utf8_across_frames() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        // "€" split over two frames, then a byte that cannot follow it
+        let mut buf = BytesMut::from(vec![0x01, 0x82, 0, 0, 0, 0, 0xe2, 0x82,
+                                          0x80, 0x81, 0, 0, 0, 0, 0xac]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let mut buf = BytesMut::from(vec![0x01, 0x81, 0, 0, 0, 0, 0xe2,
+                                          0x00, 0x81, 0, 0, 0, 0, 0x28]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let err = codec.decode(&mut buf).unwrap_err();
+        let err = WebSocketError::from_io(&err).unwrap();
+        assert_eq!(err.close_code(), CloseCode::InvalidPayload);
+    }
+
+    #[test]
+    fn fragmentation_checked() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        // Non-final binary, ping, then a text frame before the binary finished
+        let mut buf = BytesMut::from(vec![0x02, 0x81, 0, 0, 0, 0, 1,
+                                          0x89, 0x80, 0, 0, 0, 0,
+                                          0x81, 0x81, 0, 0, 0, 0, b'x']);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let err = codec.decode(&mut buf).unwrap_err();
+        let err = WebSocketError::from_io(&err).unwrap();
+        assert_eq!(err.close_code(), CloseCode::ProtocolError);
+    }
+
+    #[test]
+    fn close_event() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::from(vec![0x88, 0x85, 0, 0, 0, 0, 0x03, 0xe9, b'b', b'y', b'e']);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Close(Some((CloseCode::GoingAway, ref reason))))) => {
+                assert_eq!(reason, "bye")
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        assert_eq!(codec.close_state(), CloseState::CloseReceived);
+
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::from(vec![0x88, 0x80, 0, 0, 0, 0]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Close(None))) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_close_payload() {
+        // 1005 must never be sent
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::from(vec![0x88, 0x82, 0, 0, 0, 0, 0x03, 0xed]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        let err = WebSocketError::from_io(&err).unwrap();
+        assert_eq!(err.close_code(), CloseCode::ProtocolError);
+
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::from(vec![0x88, 0x81, 0, 0, 0, 0, 0x03]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn server_frames_unmasked() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("blub", Some(0x01020304)), &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0x81, 0x04, b'b', b'l', b'u', b'b'][..]);
+    }
+
+    #[test]
+    fn invalid_control_frame_not_sent() {
+        let mut codec = WebSocketCodec::new();
+        open(&mut codec);
+        let mut buf = BytesMut::with_capacity(0);
+        let mut close = new_close_frame(CloseCode::Normal, "", None);
+        close.header.is_final = false;
+        let err = codec.encode(close, &mut buf).unwrap_err();
+        match WebSocketError::from_io(&err) {
+            Some(&WebSocketError::FragmentedControlFrame) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert!(buf.is_empty());
+        assert_eq!(codec.close_state(), CloseState::Open);
+        assert!(codec.encode(new_ping_frame(&[0; 126], None), &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn ping_answered_after_close_sent() {
+        let mut input = upgrade_request("").to_vec();
+        input.extend_from_slice(&[0x89, 0x80, 0, 0, 0, 0]);
+        input.extend_from_slice(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8]);
+        let (io, output) = mock_io(&input);
+        let transport = PingPong::new(io.framed(WebSocketCodec::new()));
+
+        let (req, transport) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        assert!(match req {
+            Some(Request::Open(_)) => true,
+            _ => false,
+        });
+        let transport = transport.send(new_text_frame("dropped", None)).wait().unwrap();
+        let transport = transport.send(new_close_frame(CloseCode::Normal, "", None))
+            .wait()
+            .unwrap();
+        output.borrow_mut().clear();
+
+        // The Ping is answered and the peer's Close still arrives
+        let (req, _) = transport.into_future().wait().map_err(|(e, _)| e).unwrap();
+        match req {
+            Some(Request::Close(Some((CloseCode::Normal, _)))) => {}
+            e => panic!("unexpected request: {:?}", e),
+        }
+        assert_eq!(&output.borrow()[..], &[0x8a, 0x00][..]);
+    }
+
+    #[test]
+    fn compressed_message_size_limit() {
+        use flate2::{Compress, Compression, Flush};
+
+        let mut config = Config::new();
+        config.deflate(DeflateConfig::new()).max_message_size(1 << 20);
+        let mut codec = WebSocketCodec::with_config(config);
+        let mut buf = upgrade_request("Sec-WebSocket-Extensions: permessage-deflate\r\n");
+        codec.decode(&mut buf).unwrap();
+        codec.encode(new_text_frame("dropped", None), &mut BytesMut::with_capacity(0)).unwrap();
+
+        // 64 MiB of zeros in a frame of a few KiB
+        let mut compress = Compress::new(Compression::Default, false);
+        let mut payload = Vec::with_capacity(1 << 20);
+        compress.compress_vec(&vec![0; 64 << 20], &mut payload, Flush::Sync);
+        let len = payload.len() - 4;
+        payload.truncate(len);
+        let mut frame = new_text_frame("", Some(0));
+        frame.header.opcode = Opcode::Binary;
+        frame.header.rsv1 = true;
+        frame.header.payload_len = payload.len();
+        frame.payload = payload.into();
+        let mut buf = BytesMut::with_capacity(0);
+        encode(frame, &mut buf).unwrap();
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(WebSocketError::from_io(&err).unwrap().close_code(), CloseCode::TooBig);
+    }
+
+    #[test]
+    fn hook_adds_headers() {
+        let mut config = Config::new();
+        config.handshake_hook(|_| {
+            let mut res = HandshakeResponse::accept();
+            res.header("Set-Cookie", "session=1");
+            res
+        });
+        let mut codec = WebSocketCodec::with_config(config);
+        let mut buf = upgrade_request("");
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(_))) => {}
+            e => panic!("handshake failed: {:?}", e),
+        }
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("dropped", None), &mut buf).unwrap();
+        let res = String::from_utf8(buf.to_vec()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 101"));
+        assert!(res.contains("\r\nSet-Cookie: session=1\r\n"));
+    }
+
+    #[test]
+    fn hook_rejects() {
+        let mut config = Config::new();
+        config.handshake_hook(|handshake| match handshake.header("Authorization") {
+            Some(b"Bearer abc") => HandshakeResponse::accept(),
+            _ => {
+                let mut res = HandshakeResponse::reject(401, "Unauthorized");
+                res.body("no token");
+                res
+            }
+        });
+        let mut codec = WebSocketCodec::with_config(config);
+        let mut buf = upgrade_request("");
+        buf.extend_from_slice(&[0x81, 0x80, 0, 0, 0, 0]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Rejected(ref handshake))) => assert_eq!(handshake.path(), "/ws/chat/42"),
+            e => panic!("expected rejection: {:?}", e),
+        }
+        // Nothing after a rejected handshake is read
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("dropped", None), &mut buf).unwrap();
+        let res = String::from_utf8(buf.to_vec()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(res.ends_with("\r\n\r\nno token"));
+        assert!(codec.encode(new_text_frame("blub", None), &mut buf).is_err());
+    }
+}
+
+mod ws_client;
+mod ws_close;
+mod ws_config;
+mod ws_deflate;
+mod ws_error;
+mod ws_extension;
 mod ws_frame;
+mod ws_handshake;
+mod ws_message;
 mod ws_request;
 mod ws_response;
+mod ws_server;
+mod ws_transport;
+mod ws_utf8;
 
+pub use ws_client::{WebSocketClientCodec, connect, connect_with_config};
+pub use ws_close::{CloseCode, CloseState, close_payload, parse_close_payload};
+pub use ws_config::Config;
+pub use ws_deflate::DeflateConfig;
+pub use ws_error::WebSocketError;
+pub use ws_extension::{Extension, ExtensionNegotiator, ExtensionParams, RSV1, RSV2, RSV3};
 pub use ws_request::{Request, decode};
-pub use ws_response::{Response, encode};
-pub use ws_frame::{new_text_frame, Opcode, Frame};
+pub use ws_response::{Response, encode, encode_fragmented};
+pub use ws_server::{Connection, Sender, accept, accept_with_config};
+pub use ws_handshake::{Handshake, HandshakeResponse};
+pub use ws_frame::{new_text_frame, new_ping_frame, new_pong_frame, new_close_frame, mask_in_place,
+                   Opcode, Frame};
+pub use ws_message::{Message, MessageCodec, MessageFrames};
+pub use ws_transport::{KeepAlive, PingPong};
+pub use ws_utf8::Utf8Validator;
 
-pub struct WebSocket;
+/// The `tokio_proto` pipeline protocol, which answers every request with
+/// exactly one response. Use `accept` and `Connection` to push frames or
+/// leave requests unanswered.
+pub struct WebSocket {
+    config: Config,
+}
+
+impl WebSocket {
+    pub fn new() -> WebSocket {
+        WebSocket::with_config(Config::new())
+    }
+
+    pub fn with_config(config: Config) -> WebSocket {
+        WebSocket { config: config }
+    }
+
+    /// The same protocol for `TcpStream`s, filling in `Handshake::peer_addr`
+    /// from the socket.
+    pub fn with_peer_addr(self) -> TcpWebSocket {
+        TcpWebSocket { inner: self }
+    }
+
+    fn transport<T>(&self, io: T, peer_addr: Option<SocketAddr>) -> PingPong<Framed<T, WebSocketCodec>>
+        where T: AsyncRead + AsyncWrite
+    {
+        let mut codec = WebSocketCodec::with_config(self.config.clone());
+        if let Some(addr) = peer_addr {
+            codec.peer_addr(addr);
+        }
+        let mut transport = PingPong::new(io.framed(codec));
+        transport.pong_events(self.config.get_pong_events());
+        transport
+    }
+}
+
+/// A `WebSocket` that knows the address of its peer, see
+/// `WebSocket::with_peer_addr`.
+pub struct TcpWebSocket {
+    inner: WebSocket,
+}
 
 #[derive(Debug)]
 enum WebSocketState {
     Http(),
     Upgrade(String),
+    Respond(),
+    Reject(HandshakeResponse),
     Connected(),
+    Closed(),
 }
 
 impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for WebSocket {
     type Request = Request;
     type Response = Response;
-    type Transport = Framed<T, WebSocketCodec>;
-    type BindTransport = io::Result<Framed<T, WebSocketCodec>>;
+    type Transport = PingPong<Framed<T, WebSocketCodec>>;
+    type BindTransport = io::Result<PingPong<Framed<T, WebSocketCodec>>>;
+
+    fn bind_transport(&self, io: T) -> io::Result<PingPong<Framed<T, WebSocketCodec>>> {
+        Ok(self.transport(io, None))
+    }
+}
+
+impl ServerProto<TcpStream> for TcpWebSocket {
+    type Request = Request;
+    type Response = Response;
+    type Transport = PingPong<Framed<TcpStream, WebSocketCodec>>;
+    type BindTransport = io::Result<PingPong<Framed<TcpStream, WebSocketCodec>>>;
 
-    fn bind_transport(&self, io: T) -> io::Result<Framed<T, WebSocketCodec>> {
-        Ok(io.framed(WebSocketCodec::new()))
+    fn bind_transport(&self, io: TcpStream) -> io::Result<PingPong<Framed<TcpStream, WebSocketCodec>>> {
+        let peer_addr = io.peer_addr().ok();
+        Ok(self.inner.transport(io, peer_addr))
     }
 }
 
 pub struct WebSocketCodec {
     state: WebSocketState,
     http_codec: HttpCodec,
+    config: Config,
+    close_state: CloseState,
+    // Extra headers for the 101 response
+    response_headers: Vec<(String, String)>,
+    extensions: Extensions,
+    incoming: IncomingFrames,
+    peer_addr: Option<SocketAddr>,
+    // States that follow `state`, for requests pipelined behind the answer
+    // to a plain HTTP request
+    queued: VecDeque<WebSocketState>,
+    // Answers to plain HTTP requests, one for each Respond state
+    http_responses: VecDeque<tokio_minihttp::Response>,
+}
+
+impl MessageFrames for WebSocketCodec {
+    fn reassemble_messages(&mut self) {
+        self.incoming.reassemble_messages();
+    }
 }
 
 impl WebSocketCodec {
     pub fn new() -> WebSocketCodec {
+        WebSocketCodec::with_config(Config::new())
+    }
+
+    pub fn with_config(config: Config) -> WebSocketCodec {
         WebSocketCodec {
             state: WebSocketState::Http(),
             http_codec: HttpCodec,
+            config: config,
+            close_state: CloseState::Open,
+            response_headers: Vec::new(),
+            extensions: Extensions::new(),
+            // Clients have to mask every frame
+            incoming: IncomingFrames::new(true),
+            peer_addr: None,
+            queued: VecDeque::new(),
+            http_responses: VecDeque::new(),
         }
     }
+
+    /// Sets the address reported in the `Handshake` of `Request::Open`.
+    pub fn peer_addr(&mut self, addr: SocketAddr) -> &mut WebSocketCodec {
+        self.peer_addr = Some(addr);
+        self
+    }
+
+    fn negotiate_extensions(&mut self, offers: &str) {
+        let (extensions, response) = Extensions::accept_offers(self.config.get_extensions(), offers);
+        self.extensions = extensions;
+        if let Some(response) = response {
+            self.response_headers.push(("Sec-WebSocket-Extensions".to_string(), response));
+        }
+    }
+
+    fn negotiate_protocol(&mut self, offers: &[String]) -> Option<String> {
+        let protocol = self.config.select_protocol(offers);
+        if let Some(ref protocol) = protocol {
+            self.response_headers.push(("Sec-WebSocket-Protocol".to_string(), protocol.clone()));
+        }
+        protocol
+    }
+
+    /// Moves to `state` once every response owed so far has been written.
+    fn enter(&mut self, state: WebSocketState) {
+        match self.state {
+            WebSocketState::Http() => self.state = state,
+            _ => self.queued.push_back(state),
+        }
+    }
+
+    /// Validates an upgrade request and negotiates the connection's
+    /// extensions and subprotocol.
+    fn handshake(&mut self, req: &tokio_minihttp::Request) -> Request {
+        let mut handshake = Handshake::from_http(req);
+        handshake.peer_addr = self.peer_addr;
+        let is_upgrade = match handshake.header("Upgrade") {
+            Some(value) => ws_handshake::header_has_token(value, "websocket"),
+            None => false,
+        };
+        if !is_upgrade {
+            if let Some(res) = self.config.handle_http(req) {
+                self.http_responses.push_back(res);
+                self.enter(WebSocketState::Respond());
+                return Request::Http(handshake);
+            }
+        }
+        let key = match ws_handshake::validate(&handshake, req.version()) {
+            Ok(key) => key,
+            Err(res) => {
+                self.enter(WebSocketState::Reject(res));
+                return Request::Rejected(handshake);
+            }
+        };
+
+        let mut extensions = Vec::new();
+        let mut protocols = Vec::new();
+        for &(ref header, ref value) in &handshake.headers {
+            if header.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+                extensions.push(String::from_utf8_lossy(value).into_owned());
+            } else if header.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+                let value = String::from_utf8_lossy(value);
+                protocols.extend(value.split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_string));
+            }
+        }
+        self.negotiate_extensions(&extensions.join(", "));
+        handshake.protocol = self.negotiate_protocol(&protocols);
+
+        let res = self.config.check_handshake(&handshake);
+        if !res.is_accepted() {
+            self.enter(WebSocketState::Reject(res));
+            return Request::Rejected(handshake);
+        }
+        self.response_headers.extend(res.headers);
+        self.enter(WebSocketState::Upgrade(key));
+        Request::Open(handshake)
+    }
+
+    pub fn close_state(&self) -> CloseState {
+        self.close_state
+    }
 }
 
 impl Decoder for WebSocketCodec {
@@ -61,25 +842,30 @@ impl Decoder for WebSocketCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
-        match self.state {
-            WebSocketState::Http() => {
-                let req = self.http_codec.decode(buf);
-                match req {
-                    Ok(Some(req)) => {
-                        for (header, value) in req.headers() {
-                            if header == "Sec-WebSocket-Key" {
-                                let value_str = String::from_utf8(value.to_vec()).unwrap();
-                                self.state = WebSocketState::Upgrade(value_str);
-                                let req = Request::Open();
-                                return Ok(Some(req));
-                            }
-                        }
-                        Ok(None)
-                    }
-                    _ => Ok(None),
+        // `Framed` only decodes again once more bytes arrive, so a request
+        // pipelined behind a plain HTTP one is read before its answer is
+        // written, and what follows it goes by the state it will lead to
+        let reading = self.queued.back().unwrap_or(&self.state);
+        match *reading {
+            WebSocketState::Http() |
+            WebSocketState::Respond() => {
+                match try!(self.http_codec.decode(buf)) {
+                    Some(req) => Ok(Some(self.handshake(&req))),
+                    None => Ok(None),
                 }
             }
-            _ => ws_request::decode(buf),
+            // Wait for the rejection to be written
+            WebSocketState::Reject(_) => {
+                buf.clear();
+                Ok(None)
+            }
+            // The response said `Connection: close`, so whatever the peer
+            // sends next, or its end of the stream, ends the connection
+            WebSocketState::Closed() => {
+                let reason = "handshake rejected".to_string();
+                Err(WebSocketError::HandshakeFailed(reason).into())
+            }
+            _ => self.incoming.decode(buf, &self.config, &mut self.extensions, &mut self.close_state),
         }
     }
 }
@@ -91,14 +877,40 @@ impl Encoder for WebSocketCodec {
     fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
         self.state = match self.state {
             WebSocketState::Http() => {
-                return Err(io::Error::new(io::ErrorKind::Other, "pls no"));
+                let reason = "handshake not complete".to_string();
+                return Err(WebSocketError::HandshakeFailed(reason).into());
             }
             WebSocketState::Upgrade(ref key) => {
-                try!(self.http_codec.encode(ws_response::make_accept(&key), buf));
+                let mut res = ws_response::make_accept(&key);
+                for &(ref name, ref value) in &self.response_headers {
+                    res.header(name, value);
+                }
+                try!(self.http_codec.encode(res, buf));
                 WebSocketState::Connected()
             }
+            WebSocketState::Respond() => {
+                if let Some(res) = self.http_responses.pop_front() {
+                    try!(self.http_codec.encode(res, buf));
+                }
+                self.queued.pop_front().unwrap_or(WebSocketState::Http())
+            }
+            WebSocketState::Reject(ref res) => {
+                try!(self.http_codec.encode(res.to_http(), buf));
+                WebSocketState::Closed()
+            }
+            WebSocketState::Closed() => {
+                let reason = "handshake rejected".to_string();
+                return Err(WebSocketError::HandshakeFailed(reason).into());
+            }
             WebSocketState::Connected() => {
-                ws_response::encode(msg, buf);
+                let frames = try!(outgoing_frames(msg,
+                                                  &self.config,
+                                                  &mut self.extensions,
+                                                  &mut self.close_state));
+                // Servers never mask their frames
+                for frame in frames {
+                    try!(ws_response::encode(frame, buf));
+                }
                 WebSocketState::Connected()
             }
         };
@@ -0,0 +1,500 @@
+use std::io;
+
+use base64;
+use bytes::{BytesMut, BigEndian, ByteOrder};
+use futures::{Future, Stream};
+use httparse;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_io::codec::{Encoder, Decoder, Framed};
+use tokio_io::{io as async_io, AsyncRead, AsyncWrite};
+
+use ws_close::CloseState;
+use ws_config::Config;
+use ws_error::WebSocketError;
+use ws_extension::Extensions;
+use ws_handshake::{Handshake, header_has_token};
+use ws_message::MessageFrames;
+use ws_request::{IncomingFrames, Request, outgoing_frames};
+use ws_response::{self, Response, hash_key};
+
+#[cfg(test)]
+mod tests {
+    use ws_deflate::DeflateConfig;
+    use ws_frame::{Opcode, new_ping_frame, new_text_frame};
+    use ws_request;
+
+    use super::*;
+
+    const RFC_KEY: &'static str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn accept_response(accept: &str) -> BytesMut {
+        let res = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Accept: {}\r\n\r\n",
+                          accept);
+        BytesMut::from(res.as_bytes())
+    }
+
+    /// A codec that has completed the RFC 6455 example handshake.
+    fn connected_codec() -> WebSocketClientCodec {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = accept_response("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        codec.decode(&mut buf).unwrap();
+        codec
+    }
+
+    #[test]
+    fn upgrade_request_rfc_example() {
+        let codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let expected = "GET /chat HTTP/1.1\r\n\
+                        Host: server.example.com\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(codec.upgrade_request(), expected.as_bytes());
+    }
+
+    #[test]
+    fn accept_then_frame() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = accept_response("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        buf.extend_from_slice(&[0x81, 0x02, b'h', b'i']);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(ref handshake))) if handshake.protocol.is_none() => {}
+            e => panic!("handshake failed: {:?}", e),
+        }
+        let frame = match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(frame))) => frame,
+            e => panic!("decode failed: {:?}", e),
+        };
+        assert_eq!(frame.header.opcode, Opcode::Text);
+        assert_eq!(frame.payload_string().unwrap(), "hi");
+    }
+
+    #[test]
+    fn partial_accept() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Ok(None) => {}
+            e => panic!("expected partial: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn wrong_accept() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = accept_response("dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_before_accept() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = BytesMut::with_capacity(0);
+        assert!(codec.encode(new_text_frame("blub", None), &mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_masks_frames() {
+        let mut codec = connected_codec();
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("blub", None), &mut buf).unwrap();
+        assert_eq!(buf[0], 0x81);
+        assert_eq!(buf[1], 0x84);
+
+        let frame = match ws_request::decode(&mut buf) {
+            Ok(Some(Request::Frame(frame))) => frame,
+            e => panic!("decode failed: {:?}", e),
+        };
+        assert_eq!(frame.payload_string().unwrap(), "blub");
+    }
+
+    #[test]
+    fn encode_ignores_callers_key() {
+        let mut codec = connected_codec();
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("blub", Some(0x11121314)), &mut buf).unwrap();
+        codec.encode(new_text_frame("blub", Some(0x11121314)), &mut buf).unwrap();
+        let mut keys = Vec::new();
+        while let Ok(Some(Request::Frame(frame))) = ws_request::decode(&mut buf) {
+            assert_eq!(frame.payload_string().unwrap(), "blub");
+            keys.push(frame.header.masking_key);
+        }
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|&key| key != 0x11121314));
+        assert!(keys[0] != keys[1]);
+    }
+
+    #[test]
+    fn deflate_negotiated() {
+        let mut config = Config::new();
+        config.deflate(DeflateConfig::new());
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        codec.config = config;
+        assert!(String::from_utf8(codec.upgrade_request())
+            .unwrap()
+            .contains("\r\nSec-WebSocket-Extensions: permessage-deflate\r\n"));
+
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n\
+                                         Upgrade: websocket\r\n\
+                                         Connection: Upgrade\r\n\
+                                         Sec-WebSocket-Extensions: permessage-deflate\r\n\
+                                         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]);
+        codec.decode(&mut buf).unwrap();
+        // "Hello" compressed, RFC 7692 §7.2.3.1
+        buf.extend_from_slice(&[0xc1, 0x07, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+        let frame = match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(frame))) => frame,
+            e => panic!("decode failed: {:?}", e),
+        };
+        assert_eq!(frame.payload_string().unwrap(), "Hello");
+    }
+
+    #[test]
+    fn unexpected_extension() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n\
+                                         Upgrade: websocket\r\n\
+                                         Connection: Upgrade\r\n\
+                                         Sec-WebSocket-Extensions: permessage-deflate\r\n\
+                                         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn protocol_negotiated() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        codec.config.protocols(&["v2.json", "mqtt"]);
+        assert!(String::from_utf8(codec.upgrade_request())
+            .unwrap()
+            .contains("\r\nSec-WebSocket-Protocol: v2.json, mqtt\r\n"));
+
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n\
+                                         Upgrade: websocket\r\n\
+                                         Connection: Upgrade\r\n\
+                                         Sec-WebSocket-Protocol: mqtt\r\n\
+                                         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Open(ref handshake))) if handshake.protocol == Some("mqtt".to_string()) => {}
+            e => panic!("handshake failed: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn unexpected_protocol() {
+        let mut codec = WebSocketClientCodec::with_key("server.example.com", "/chat", RFC_KEY);
+        codec.config.protocols(&["v2.json"]);
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n\
+                                         Upgrade: websocket\r\n\
+                                         Connection: Upgrade\r\n\
+                                         Sec-WebSocket-Protocol: mqtt\r\n\
+                                         Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn masked_server_frame() {
+        let mut codec = connected_codec();
+        let mut buf = BytesMut::from(vec![0x81, 0x81, 1, 2, 3, 4, b'x']);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn unowned_rsv_bits() {
+        let mut codec = connected_codec();
+        let mut buf = BytesMut::from(vec![0xc1, 0x01, b'x']);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn invalid_control_frames() {
+        let mut codec = connected_codec();
+        let mut buf = BytesMut::from(vec![0x09, 0x00]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::with_capacity(0);
+        assert!(codec.encode(new_ping_frame(&[0; 126], None), &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_masks_each_fragment() {
+        let mut codec = connected_codec();
+        codec.config.max_outgoing_frame_size(3);
+        let mut buf = BytesMut::with_capacity(0);
+        codec.encode(new_text_frame("blub", Some(0x11121314)), &mut buf).unwrap();
+
+        let mut text = String::new();
+        let mut keys = Vec::new();
+        while let Ok(Some(Request::Frame(frame))) = ws_request::decode(&mut buf) {
+            assert!(frame.header.is_masked);
+            keys.push(frame.header.masking_key);
+            text.push_str(&frame.payload_string().unwrap());
+        }
+        assert_eq!(text, "blub");
+        // Each fragment gets a key of its own instead of the caller's
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|&key| key != 0x11121314));
+    }
+}
+
+#[derive(Debug)]
+enum ClientState {
+    Upgrade(String),
+    Connected(),
+}
+
+pub struct WebSocketClientCodec {
+    state: ClientState,
+    host: String,
+    path: String,
+    rng: SystemRandom,
+    config: Config,
+    close_state: CloseState,
+    extensions: Extensions,
+    incoming: IncomingFrames,
+}
+
+impl MessageFrames for WebSocketClientCodec {
+    fn reassemble_messages(&mut self) {
+        self.incoming.reassemble_messages();
+    }
+}
+
+fn random_bytes(rng: &SystemRandom, dest: &mut [u8]) -> io::Result<()> {
+    rng.fill(dest).map_err(|_| io::Error::new(io::ErrorKind::Other, "random source failed"))
+}
+
+impl WebSocketClientCodec {
+    /// Creates a codec for connecting to `path` on `host` with a freshly
+    /// generated `Sec-WebSocket-Key`.
+    pub fn new(host: &str, path: &str) -> io::Result<WebSocketClientCodec> {
+        WebSocketClientCodec::with_config(host, path, Config::new())
+    }
+
+    pub fn with_config(host: &str, path: &str, config: Config) -> io::Result<WebSocketClientCodec> {
+        let rng = SystemRandom::new();
+        let mut nonce = [0u8; 16];
+        try!(random_bytes(&rng, &mut nonce));
+        let mut codec = WebSocketClientCodec::with_key(host, path, &base64::encode(&nonce));
+        codec.config = config;
+        Ok(codec)
+    }
+
+    fn with_key(host: &str, path: &str, b64_key: &str) -> WebSocketClientCodec {
+        WebSocketClientCodec {
+            state: ClientState::Upgrade(b64_key.to_string()),
+            host: host.to_string(),
+            path: path.to_string(),
+            rng: SystemRandom::new(),
+            config: Config::new(),
+            close_state: CloseState::Open,
+            extensions: Extensions::new(),
+            // Servers must not mask their frames
+            incoming: IncomingFrames::new(false),
+        }
+    }
+
+    pub fn close_state(&self) -> CloseState {
+        self.close_state
+    }
+
+    fn encode_frame(&mut self, mut msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+        // Every frame sent by a client has to be masked, with a key the
+        // application can't predict (RFC 6455 5.3), so the caller's is ignored
+        let mut key = [0u8; 4];
+        try!(random_bytes(&self.rng, &mut key));
+        msg.header.is_masked = true;
+        msg.header.masking_key = BigEndian::read_u32(&key);
+        ws_response::encode(msg, buf)
+    }
+
+    /// The HTTP upgrade request that has to be written to the server before
+    /// any frames can be exchanged.
+    pub fn upgrade_request(&self) -> Vec<u8> {
+        let key = match self.state {
+            ClientState::Upgrade(ref key) => key,
+            ClientState::Connected() => return Vec::new(),
+        };
+        let mut request = format!("GET {} HTTP/1.1\r\n\
+                                   Host: {}\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: {}\r\n\
+                                   Sec-WebSocket-Version: 13\r\n",
+                                  self.path,
+                                  self.host,
+                                  key);
+        if let Some(offer) = Extensions::offer(self.config.get_extensions()) {
+            request.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", offer));
+        }
+        if !self.config.get_protocols().is_empty() {
+            request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n",
+                                      self.config.get_protocols().join(", ")));
+        }
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+}
+
+fn handshake_error(reason: &str) -> io::Error {
+    WebSocketError::HandshakeFailed(reason.to_string()).into()
+}
+
+/// What the server selected in its response to the upgrade request.
+struct Accepted {
+    len: usize,
+    headers: Vec<(String, Vec<u8>)>,
+    extensions: Option<String>,
+    protocol: Option<String>,
+}
+
+/// Validates the server's response to the upgrade request.
+fn check_accept(b64_key: &str, buf: &mut BytesMut) -> io::Result<Option<Accepted>> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut res = httparse::Response::new(&mut headers);
+    let len = match res.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(_) => return Err(handshake_error("invalid upgrade response")),
+    };
+    if res.code != Some(101) {
+        return Err(handshake_error("server refused upgrade"));
+    }
+
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut accept = false;
+    let mut extensions: Option<String> = None;
+    let mut protocol = None;
+    let expected_accept = hash_key(b64_key);
+    for header in res.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Upgrade") {
+            upgrade = header_has_token(header.value, "websocket");
+        } else if header.name.eq_ignore_ascii_case("Connection") {
+            connection = header_has_token(header.value, "Upgrade");
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Accept") {
+            accept = header.value == expected_accept.as_bytes();
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+            let value = String::from_utf8_lossy(header.value);
+            extensions = Some(match extensions {
+                Some(previous) => format!("{}, {}", previous, value),
+                None => value.into_owned(),
+            });
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+            if protocol.is_some() {
+                return Err(handshake_error("more than one subprotocol in response"));
+            }
+            protocol = Some(String::from_utf8_lossy(header.value).trim().to_string());
+        }
+    }
+    if !upgrade || !connection {
+        return Err(handshake_error("missing upgrade headers"));
+    }
+    if !accept {
+        return Err(handshake_error("invalid Sec-WebSocket-Accept"));
+    }
+    Ok(Some(Accepted {
+        len: len,
+        headers: res.headers
+            .iter()
+            .map(|header| (header.name.to_string(), header.value.to_vec()))
+            .collect(),
+        extensions: extensions,
+        protocol: protocol,
+    }))
+}
+
+impl Decoder for WebSocketClientCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        let accepted = match self.state {
+            ClientState::Upgrade(ref key) => {
+                match try!(check_accept(key, buf)) {
+                    Some(accepted) => accepted,
+                    None => return Ok(None),
+                }
+            }
+            ClientState::Connected() => {
+                return self.incoming
+                    .decode(buf, &self.config, &mut self.extensions, &mut self.close_state);
+            }
+        };
+        if let Some(extensions) = accepted.extensions {
+            self.extensions = try!(Extensions::accept_response(self.config.get_extensions(),
+                                                                &extensions));
+        }
+        if let Some(ref protocol) = accepted.protocol {
+            if !self.config.get_protocols().contains(protocol) {
+                return Err(handshake_error("unexpected subprotocol in response"));
+            }
+        }
+        buf.split_to(accepted.len);
+        self.state = ClientState::Connected();
+        Ok(Some(Request::Open(Handshake {
+            method: "GET".to_string(),
+            uri: self.path.clone(),
+            headers: accepted.headers,
+            peer_addr: None,
+            protocol: accepted.protocol,
+        })))
+    }
+}
+
+impl Encoder for WebSocketClientCodec {
+    type Item = Response;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+        match self.state {
+            ClientState::Upgrade(_) => return Err(handshake_error("handshake not complete")),
+            ClientState::Connected() => {}
+        }
+        let frames = try!(outgoing_frames(msg,
+                                          &self.config,
+                                          &mut self.extensions,
+                                          &mut self.close_state));
+        for frame in frames {
+            try!(self.encode_frame(frame, buf));
+        }
+        Ok(())
+    }
+}
+
+/// Performs the opening handshake over `io`, resolving to the server's
+/// response, with the subprotocol it selected, and a framed transport once
+/// the server has accepted the upgrade.
+pub fn connect<T>(io: T,
+                  host: &str,
+                  path: &str)
+                  -> Box<Future<Item = (Handshake, Framed<T, WebSocketClientCodec>), Error = io::Error>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    connect_with_config(io, host, path, Config::new())
+}
+
+pub fn connect_with_config<T>
+    (io: T,
+     host: &str,
+     path: &str,
+     config: Config)
+     -> Box<Future<Item = (Handshake, Framed<T, WebSocketClientCodec>), Error = io::Error>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let codec = match WebSocketClientCodec::with_config(host, path, config) {
+        Ok(codec) => codec,
+        Err(e) => return Box::new(::futures::future::err(e)),
+    };
+    let request = codec.upgrade_request();
+    Box::new(async_io::write_all(io, request)
+        .and_then(move |(io, _)| io.framed(codec).into_future().map_err(|(e, _)| e))
+        .and_then(|(req, transport)| match req {
+            Some(Request::Open(handshake)) => Ok((handshake, transport)),
+            _ => Err(handshake_error("connection closed during handshake")),
+        }))
+}
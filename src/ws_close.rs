@@ -0,0 +1,219 @@
+use std::io;
+
+use bytes::{BigEndian, ByteOrder};
+
+use ws_error::WebSocketError;
+use ws_frame::{Frame, Opcode};
+
+#[cfg(test)]
+mod tests {
+    use ws_frame::{new_close_frame, new_pong_frame, new_text_frame};
+
+    use super::*;
+
+    #[test]
+    fn close_code_round_trip() {
+        for &code in &[1000u16, 1001, 1002, 1003, 1007, 1008, 1009, 1011, 3000, 4999, 1010] {
+            assert_eq!(u16::from(CloseCode::from(code)), code);
+        }
+        assert_eq!(CloseCode::from(1009), CloseCode::TooBig);
+        assert_eq!(CloseCode::from(4000), CloseCode::Application(4000));
+    }
+
+    #[test]
+    fn build_and_parse_payload() {
+        let payload = close_payload(CloseCode::GoingAway, "bye");
+        assert_eq!(payload, vec![0x03, 0xe9, b'b', b'y', b'e']);
+        let (code, reason) = parse_close_payload(&payload).unwrap().unwrap();
+        assert_eq!(code, CloseCode::GoingAway);
+        assert_eq!(reason, "bye");
+    }
+
+    #[test]
+    fn parse_empty_payload() {
+        assert!(parse_close_payload(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_invalid_payloads() {
+        assert!(parse_close_payload(&[0x03]).is_err());
+        assert!(parse_close_payload(&[0x03, 0xe8, 0xff]).is_err());
+    }
+
+    #[test]
+    fn codes_not_on_the_wire() {
+        for &code in &[0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999] {
+            let err = parse_close_payload(&close_payload(CloseCode::from(code), "")).unwrap_err();
+            match WebSocketError::from_io(&err) {
+                Some(&WebSocketError::ProtocolError(_)) => {}
+                e => panic!("unexpected error for {}: {:?}", code, e),
+            }
+        }
+        for &code in &[1000u16, 1003, 1007, 1011, 1014, 3000, 4999] {
+            assert!(parse_close_payload(&close_payload(CloseCode::from(code), "")).is_ok(),
+                    "rejected {}",
+                    code);
+        }
+    }
+
+    #[test]
+    fn no_data_after_close() {
+        let mut state = CloseState::Open;
+        state.received(&new_close_frame(CloseCode::Normal, "", None));
+        assert_eq!(state, CloseState::CloseReceived);
+        assert!(state.sending(&new_text_frame("blub", None)).is_ok());
+        assert!(state.sending(&new_close_frame(CloseCode::Normal, "", None)).is_ok());
+        assert_eq!(state, CloseState::Closed);
+        assert!(state.sending(&new_text_frame("blub", None)).is_err());
+    }
+
+    #[test]
+    fn pong_after_close_sent() {
+        let mut state = CloseState::Open;
+        state.sending(&new_close_frame(CloseCode::Normal, "", None)).unwrap();
+        assert!(state.sending(&new_pong_frame(b"", None)).is_ok());
+        assert!(state.sending(&new_text_frame("blub", None)).is_err());
+        assert!(state.sending(&new_close_frame(CloseCode::Normal, "", None)).is_err());
+        assert_eq!(state, CloseState::CloseSent);
+    }
+}
+
+/// Status codes sent in Close frames, see RFC 6455 §7.4.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    Unsupported,
+    InvalidPayload,
+    Policy,
+    TooBig,
+    InternalError,
+    /// Codes 3000-4999, reserved for libraries and applications.
+    Application(u16),
+    /// Any other code.
+    Other(u16),
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::Policy,
+            1009 => CloseCode::TooBig,
+            1011 => CloseCode::InternalError,
+            3000...4999 => CloseCode::Application(code),
+            _ => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::Policy => 1008,
+            CloseCode::TooBig => 1009,
+            CloseCode::InternalError => 1011,
+            CloseCode::Application(code) |
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+/// Builds the payload of a Close frame: the 2 byte status code followed by
+/// the UTF-8 reason.
+pub fn close_payload(code: CloseCode, reason: &str) -> Vec<u8> {
+    let mut payload = vec![0u8; 2];
+    BigEndian::write_u16(&mut payload, u16::from(code));
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+/// Whether `code` may be sent in a Close frame, RFC 6455 §7.4. 1005, 1006
+/// and 1015 only report what happened locally, and the rest of 1000-2999 is
+/// reserved for future versions of the protocol.
+fn allowed_on_wire(code: u16) -> bool {
+    match code {
+        1004 | 1005 | 1006 | 1015 => false,
+        1000...1015 | 3000...4999 => true,
+        _ => false,
+    }
+}
+
+/// Parses the (unmasked) payload of a Close frame. An empty payload carries
+/// no status code and yields `None`. A code that must not be sent or a
+/// reason that is not UTF-8 is an error.
+pub fn parse_close_payload(payload: &[u8]) -> io::Result<Option<(CloseCode, String)>> {
+    match payload.len() {
+        0 => return Ok(None),
+        1 => {
+            return Err(WebSocketError::ProtocolError("truncated close code".to_string()).into());
+        }
+        _ => {}
+    }
+    let code = BigEndian::read_u16(payload);
+    if !allowed_on_wire(code) {
+        let reason = format!("close code {} not allowed", code);
+        return Err(WebSocketError::ProtocolError(reason).into());
+    }
+    let code = CloseCode::from(code);
+    match String::from_utf8(payload[2..].to_vec()) {
+        Ok(reason) => Ok(Some((code, reason))),
+        Err(_) => Err(WebSocketError::InvalidUtf8.into()),
+    }
+}
+
+/// Progress of the closing handshake on one connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CloseState {
+    Open,
+    /// We sent a Close and wait for the peer's.
+    CloseSent,
+    /// The peer sent a Close and waits for ours.
+    CloseReceived,
+    /// Both sides sent a Close, the TCP connection can be dropped.
+    Closed,
+}
+
+impl CloseState {
+    /// Checks that `frame` may still be sent and records outgoing Closes.
+    /// After our Close only Pings and Pongs may follow, so that Pings which
+    /// arrive before the peer's Close can still be answered.
+    pub fn sending(&mut self, frame: &Frame) -> io::Result<()> {
+        match (*self, &frame.header.opcode) {
+            (CloseState::CloseSent, &Opcode::Ping) |
+            (CloseState::CloseSent, &Opcode::Pong) => {}
+            (CloseState::CloseSent, _) |
+            (CloseState::Closed, _) => {
+                return Err(WebSocketError::AlreadyClosed.into());
+            }
+            _ => {}
+        }
+        if frame.header.opcode == Opcode::Close {
+            *self = match *self {
+                CloseState::CloseReceived => CloseState::Closed,
+                _ => CloseState::CloseSent,
+            };
+        }
+        Ok(())
+    }
+
+    /// Records incoming Closes.
+    pub fn received(&mut self, frame: &Frame) {
+        if frame.header.opcode == Opcode::Close {
+            *self = match *self {
+                CloseState::CloseSent | CloseState::Closed => CloseState::Closed,
+                _ => CloseState::CloseReceived,
+            };
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt;
+use std::sync::Arc;
+
+use ws_deflate::DeflateConfig;
+use tokio_minihttp;
+
+use ws_extension::ExtensionNegotiator;
+use ws_handshake::{Handshake, HandshakeResponse};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offers(offers: &[&str]) -> Vec<String> {
+        offers.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn select_protocol_by_preference() {
+        let mut config = Config::new();
+        assert_eq!(config.select_protocol(&offers(&["mqtt"])), None);
+
+        config.protocols(&["v2.json", "mqtt"]);
+        assert_eq!(config.select_protocol(&offers(&["mqtt", "v2.json"])),
+                   Some("v2.json".to_string()));
+        assert_eq!(config.select_protocol(&offers(&["graphql-ws"])), None);
+        assert_eq!(config.select_protocol(&[]), None);
+    }
+
+    #[test]
+    fn select_protocol_with_selector() {
+        let mut config = Config::new();
+        config.protocol_selector(|offers| offers.last().cloned());
+        assert_eq!(config.select_protocol(&offers(&["mqtt", "graphql-ws"])),
+                   Some("graphql-ws".to_string()));
+
+        // Protocols the client did not offer are dropped
+        config.protocol_selector(|_| Some("v2.json".to_string()));
+        assert_eq!(config.select_protocol(&offers(&["mqtt"])), None);
+    }
+}
+
+/// Options shared by the server and client codecs.
+#[derive(Clone)]
+pub struct Config {
+    max_outgoing_frame_size: Option<usize>,
+    max_frame_size: usize,
+    max_message_size: usize,
+    pong_events: bool,
+    extensions: Vec<Arc<ExtensionNegotiator>>,
+    protocols: Vec<String>,
+    protocol_selector: Option<Arc<Fn(&[String]) -> Option<String> + Send + Sync>>,
+    handshake_hook: Option<Arc<Fn(&Handshake) -> HandshakeResponse + Send + Sync>>,
+    http_handler: Option<Arc<Fn(&tokio_minihttp::Request) -> tokio_minihttp::Response + Send + Sync>>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            max_outgoing_frame_size: None,
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+            pong_events: false,
+            extensions: Vec::new(),
+            protocols: Vec::new(),
+            protocol_selector: None,
+            handshake_hook: None,
+            http_handler: None,
+        }
+    }
+
+    /// Splits outgoing messages whose payload is longer than `size` into a
+    /// leading frame followed by continuation frames of at most `size` bytes.
+    ///
+    /// Panics if `size` is 0.
+    pub fn max_outgoing_frame_size(&mut self, size: usize) -> &mut Config {
+        assert!(size > 0, "frame size must be positive");
+        self.max_outgoing_frame_size = Some(size);
+        self
+    }
+
+    pub fn get_max_outgoing_frame_size(&self) -> Option<usize> {
+        self.max_outgoing_frame_size
+    }
+
+    /// Fails the connection with close code 1009 when an incoming frame
+    /// announces a payload longer than `size`. Defaults to 16 MiB.
+    pub fn max_frame_size(&mut self, size: usize) -> &mut Config {
+        self.max_frame_size = size;
+        self
+    }
+
+    pub fn get_max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Fails the connection with close code 1009 when the fragments of an
+    /// incoming message add up to more than `size` bytes. Compressed messages
+    /// are stopped while they are inflated, as soon as they would go over.
+    /// Defaults to 64 MiB.
+    pub fn max_message_size(&mut self, size: usize) -> &mut Config {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn get_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Whether the transport of `WebSocket` passes Pong frames on to the
+    /// application. Off by default, since there every request needs an
+    /// answering frame. That transport also answers Pings; the one from
+    /// `accept` does neither until it is wrapped in a `PingPong`.
+    pub fn pong_events(&mut self, enabled: bool) -> &mut Config {
+        self.pong_events = enabled;
+        self
+    }
+
+    pub fn get_pong_events(&self) -> bool {
+        self.pong_events
+    }
+
+    /// Enables the permessage-deflate extension. Clients offer it with the
+    /// given parameters, servers accept offers for it and add the given
+    /// parameters to their response.
+    pub fn deflate(&mut self, deflate: DeflateConfig) -> &mut Config {
+        self.extension(deflate)
+    }
+
+    /// Adds an extension to negotiate. Clients offer extensions in the order
+    /// they were added.
+    pub fn extension<E>(&mut self, negotiator: E) -> &mut Config
+        where E: ExtensionNegotiator + 'static
+    {
+        self.extensions.push(Arc::new(negotiator));
+        self
+    }
+
+    pub fn get_extensions(&self) -> &[Arc<ExtensionNegotiator>] {
+        &self.extensions
+    }
+
+    /// The subprotocols we speak, in order of preference. Clients offer them
+    /// in `Sec-WebSocket-Protocol`, servers pick the first one the client
+    /// offered.
+    pub fn protocols(&mut self, protocols: &[&str]) -> &mut Config {
+        self.protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn get_protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    /// Lets a server choose the subprotocol from the client's offer instead
+    /// of going by `protocols`. Returning a protocol the client did not
+    /// offer is the same as returning `None`.
+    pub fn protocol_selector<F>(&mut self, selector: F) -> &mut Config
+        where F: Fn(&[String]) -> Option<String> + Send + Sync + 'static
+    {
+        self.protocol_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Picks the subprotocol for a client that offered `offers`.
+    pub fn select_protocol(&self, offers: &[String]) -> Option<String> {
+        let selected = match self.protocol_selector {
+            Some(ref selector) => selector(offers),
+            None => self.protocols.iter().find(|p| offers.contains(p)).cloned(),
+        };
+        selected.and_then(|p| if offers.contains(&p) { Some(p) } else { None })
+    }
+
+    /// Lets a server look at each opening handshake before it is answered,
+    /// to accept it with extra response headers or reject it with an HTTP
+    /// error. Handshakes are accepted if no hook is set.
+    pub fn handshake_hook<F>(&mut self, hook: F) -> &mut Config
+        where F: Fn(&Handshake) -> HandshakeResponse + Send + Sync + 'static
+    {
+        self.handshake_hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn check_handshake(&self, handshake: &Handshake) -> HandshakeResponse {
+        match self.handshake_hook {
+            Some(ref hook) => hook(handshake),
+            None => HandshakeResponse::accept(),
+        }
+    }
+
+    /// Lets a server answer plain HTTP requests, those without
+    /// `Upgrade: websocket`, on the same port. Without a handler they get a
+    /// 400.
+    pub fn http_handler<F>(&mut self, handler: F) -> &mut Config
+        where F: Fn(&tokio_minihttp::Request) -> tokio_minihttp::Response + Send + Sync + 'static
+    {
+        self.http_handler = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn handle_http(&self, req: &tokio_minihttp::Request) -> Option<tokio_minihttp::Response> {
+        self.http_handler.as_ref().map(|handler| handler(req))
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let extensions: Vec<&str> = self.extensions.iter().map(|e| e.name()).collect();
+        f.debug_struct("Config")
+            .field("max_outgoing_frame_size", &self.max_outgoing_frame_size)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("max_message_size", &self.max_message_size)
+            .field("pong_events", &self.pong_events)
+            .field("extensions", &extensions)
+            .field("protocols", &self.protocols)
+            .field("protocol_selector", &self.protocol_selector.is_some())
+            .field("handshake_hook", &self.handshake_hook.is_some())
+            .field("http_handler", &self.http_handler.is_some())
+            .finish()
+    }
+}
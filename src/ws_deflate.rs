@@ -0,0 +1,538 @@
+use std::cmp;
+use std::io;
+
+use bytes::Bytes;
+use flate2::{Compress, Compression, Decompress, Flush, Status};
+
+use ws_error::WebSocketError;
+use ws_extension::{Extension, ExtensionNegotiator, ExtensionParams, RSV1};
+use ws_frame::{Frame, Opcode};
+
+#[cfg(test)]
+mod tests {
+    use ws_extension::parse_extensions;
+    use ws_frame::{Header, new_text_frame};
+
+    use super::*;
+
+    fn accept(config: &DeflateConfig, header: &str) -> Option<String> {
+        parse_extensions(header)
+            .into_iter()
+            .filter_map(|(_, params)| config.accept_offer(&params))
+            .map(|(_, response)| response)
+            .next()
+    }
+
+    fn response(config: &DeflateConfig, header: &str) -> io::Result<Box<Extension>> {
+        let (_, params) = parse_extensions(header).remove(0);
+        config.accept_response(&params)
+    }
+
+    fn binary_frame(is_final: bool, opcode: Opcode, payload: &[u8]) -> Frame {
+        Frame {
+            header: Header {
+                is_final: is_final,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: opcode,
+                is_masked: false,
+                payload_len: payload.len(),
+                masking_key: 0,
+            },
+            payload: Bytes::from(payload),
+        }
+    }
+
+    #[test]
+    fn accept_plain_offer() {
+        assert_eq!(accept(&DeflateConfig::new(), "permessage-deflate"),
+                   Some("permessage-deflate".to_string()));
+    }
+
+    #[test]
+    fn accept_offer_with_params() {
+        let mut config = DeflateConfig::new();
+        config.client_no_context_takeover = true;
+        assert_eq!(accept(&config,
+                          "permessage-deflate; server_no_context_takeover; \
+                           server_max_window_bits=10; client_max_window_bits"),
+                   Some("permessage-deflate; server_no_context_takeover; \
+                         client_no_context_takeover; server_max_window_bits=10"
+                       .to_string()));
+    }
+
+    #[test]
+    fn reject_bad_offers() {
+        let config = DeflateConfig::new();
+        assert!(accept(&config, "permessage-deflate; foo").is_none());
+        assert!(accept(&config, "permessage-deflate; server_max_window_bits=16").is_none());
+        assert!(accept(&config, "permessage-deflate; server_no_context_takeover=1").is_none());
+        assert!(accept(&config,
+                       "permessage-deflate; server_no_context_takeover; \
+                        server_no_context_takeover")
+            .is_none());
+        assert!(accept(&config, "permessage-deflate; foo, permessage-deflate").is_some());
+    }
+
+    #[test]
+    fn client_negotiation() {
+        let mut config = DeflateConfig::new();
+        config.server_max_window_bits = Some(10);
+        assert_eq!(config.offer(), "permessage-deflate; server_max_window_bits=10");
+
+        assert!(response(&config, "permessage-deflate; server_max_window_bits=10").is_ok());
+        assert!(response(&config, "permessage-deflate; server_max_window_bits=12").is_err());
+        assert!(response(&config, "permessage-deflate; client_max_window_bits=10").is_err());
+        assert!(response(&config, "permessage-deflate; foo").is_err());
+    }
+
+    #[test]
+    fn rfc_hello_example() {
+        // RFC 7692 §7.2.3.1
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        let mut frame = binary_frame(true, Opcode::Text, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+        frame.header.rsv1 = true;
+        let frame = server.decode_frame(frame, ::std::usize::MAX).unwrap();
+        assert!(!frame.header.rsv1);
+        assert_eq!(frame.payload_string().unwrap(), "Hello");
+    }
+
+    #[test]
+    fn round_trip_with_context_takeover() {
+        let mut client = PerMessageDeflate::new(false, DeflateConfig::new());
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        for _ in 0..3 {
+            let frame = client.encode_frame(new_text_frame("Hello Hello Hello", Some(0x11121314)))
+                .unwrap();
+            assert!(frame.header.rsv1);
+            assert!(frame.header.is_masked);
+            let frame = server.decode_frame(frame, ::std::usize::MAX).unwrap();
+            assert_eq!(frame.payload_string().unwrap(), "Hello Hello Hello");
+        }
+    }
+
+    #[test]
+    fn round_trip_fragmented() {
+        let mut config = DeflateConfig::new();
+        config.client_no_context_takeover = true;
+        let mut client = PerMessageDeflate::new(false, config.clone());
+        let mut server = PerMessageDeflate::new(true, config);
+        let first = client.encode_frame(binary_frame(false, Opcode::Binary, b"abcabc")).unwrap();
+        let last = client.encode_frame(binary_frame(true, Opcode::Continuation, b"abcabc"))
+            .unwrap();
+        assert!(first.header.rsv1);
+        assert!(!last.header.rsv1);
+
+        let mut data = server.decode_frame(first, ::std::usize::MAX).unwrap().payload;
+        data.extend(server.decode_frame(last, ::std::usize::MAX).unwrap().payload);
+        assert_eq!(data, b"abcabcabcabc".to_vec());
+    }
+
+    #[test]
+    fn small_window_not_compressed() {
+        let mut config = DeflateConfig::new();
+        config.server_max_window_bits = Some(10);
+        let mut server = PerMessageDeflate::new(true, config);
+        let frame = server.encode_frame(new_text_frame("Hello", None)).unwrap();
+        assert!(!frame.header.rsv1);
+        assert_eq!(frame.payload, b"Hello".to_vec());
+    }
+
+    fn final_block(data: &[u8]) -> Vec<u8> {
+        let mut compress = Compress::new(Compression::Default, false);
+        let mut output = Vec::with_capacity(data.len() + 64);
+        compress.compress_vec(data, &mut output, Flush::Finish);
+        output
+    }
+
+    #[test]
+    fn final_block_ends_message() {
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        let mut client = PerMessageDeflate::new(false, DeflateConfig::new());
+        let mut frame = binary_frame(true, Opcode::Text, &final_block(b"Hello"));
+        frame.header.rsv1 = true;
+        assert_eq!(server.decode_frame(frame, ::std::usize::MAX).unwrap().payload_string().unwrap(), "Hello");
+
+        // Split over two frames, with more input after the final block
+        let mut data = final_block(b"Hello");
+        data.extend_from_slice(&TRAILER);
+        let mut first = binary_frame(false, Opcode::Text, &data);
+        first.header.rsv1 = true;
+        let mut payload = server.decode_frame(first, ::std::usize::MAX).unwrap().payload;
+        let last = binary_frame(true, Opcode::Continuation, b"ignored");
+        payload.extend(server.decode_frame(last, ::std::usize::MAX).unwrap().payload);
+        assert_eq!(payload, b"Hello".to_vec());
+
+        // The next message starts a new stream
+        let frame = client.encode_frame(new_text_frame("Hello again", None)).unwrap();
+        assert_eq!(server.decode_frame(frame, ::std::usize::MAX).unwrap().payload_string().unwrap(),
+                   "Hello again");
+    }
+
+    #[test]
+    fn inflate_limited() {
+        let mut client = PerMessageDeflate::new(false, DeflateConfig::new());
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        // 16 MiB of zeros compress to a few KiB
+        let frame = client.encode_frame(binary_frame(true, Opcode::Binary, &vec![0; 16 << 20]))
+            .unwrap();
+        assert!(frame.payload.len() < 64 << 10);
+        let err = server.decode_frame(frame, 1 << 20).unwrap_err();
+        match WebSocketError::from_io(&err) {
+            Some(&WebSocketError::PayloadTooLarge) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let mut client = PerMessageDeflate::new(false, DeflateConfig::new());
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        let frame = client.encode_frame(binary_frame(true, Opcode::Binary, &[0; 100])).unwrap();
+        assert!(server.decode_frame(frame.clone(), 99).is_err());
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        assert_eq!(server.decode_frame(frame, 100).unwrap().payload.len(), 100);
+    }
+
+    #[test]
+    fn compressed_continuation_rejected() {
+        let mut server = PerMessageDeflate::new(true, DeflateConfig::new());
+        let mut frame = binary_frame(true, Opcode::Continuation, b"");
+        frame.header.rsv1 = true;
+        assert!(server.decode_frame(frame, ::std::usize::MAX).is_err());
+    }
+}
+
+pub const EXTENSION_NAME: &'static str = "permessage-deflate";
+
+// Trailer of a sync flush, stripped from every compressed message
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// miniz always compresses with the largest window
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// Parameters of the permessage-deflate extension (RFC 7692).
+///
+/// A client offers these parameters, a server uses them to decide what to add
+/// to its response on top of what the client asked for.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeflateConfig {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+    pub client_max_window_bits: Option<u8>,
+}
+
+impl DeflateConfig {
+    pub fn new() -> DeflateConfig {
+        DeflateConfig {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: None,
+            client_max_window_bits: None,
+        }
+    }
+
+    fn to_header(&self) -> String {
+        let mut header = EXTENSION_NAME.to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            header.push_str(&format!("; server_max_window_bits={}", bits));
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(bits) if bits >= 8 && bits <= 15 => Some(bits),
+        _ => None,
+    }
+}
+
+/// Reads permessage-deflate parameters, `None` if any of them is unknown,
+/// duplicated or has an invalid value. `client_max_window_bits` may come
+/// without a value in an offer, in which case it is returned as `Some(15)`.
+fn parse_params(params: &ExtensionParams) -> Option<DeflateConfig> {
+    let mut config = DeflateConfig::new();
+    for &(ref key, ref value) in params {
+        match (key.as_str(), value.as_ref()) {
+            ("server_no_context_takeover", None) if !config.server_no_context_takeover => {
+                config.server_no_context_takeover = true;
+            }
+            ("client_no_context_takeover", None) if !config.client_no_context_takeover => {
+                config.client_no_context_takeover = true;
+            }
+            ("server_max_window_bits", Some(value)) if config.server_max_window_bits.is_none() => {
+                config.server_max_window_bits = parse_window_bits(value);
+                if config.server_max_window_bits.is_none() {
+                    return None;
+                }
+            }
+            ("client_max_window_bits", None) if config.client_max_window_bits.is_none() => {
+                config.client_max_window_bits = Some(MAX_WINDOW_BITS);
+            }
+            ("client_max_window_bits", Some(value)) if config.client_max_window_bits.is_none() => {
+                config.client_max_window_bits = parse_window_bits(value);
+                if config.client_max_window_bits.is_none() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(config)
+}
+
+fn negotiation_error(reason: &str) -> io::Error {
+    WebSocketError::HandshakeFailed(reason.to_string()).into()
+}
+
+fn compression_error() -> io::Error {
+    WebSocketError::ProtocolError("invalid compressed data".to_string()).into()
+}
+
+/// The permessage-deflate state of one connection.
+pub struct PerMessageDeflate {
+    is_server: bool,
+    params: DeflateConfig,
+    compress: Compress,
+    decompress: Decompress,
+    // Whether the outgoing message in progress is compressed
+    deflating: bool,
+    // Whether the incoming message in progress is compressed
+    inflating: bool,
+    // Whether the peer ended the DEFLATE stream of the incoming message with
+    // a final block, RFC 7692 §7.2.3.3
+    stream_ended: bool,
+}
+
+impl PerMessageDeflate {
+    fn new(is_server: bool, params: DeflateConfig) -> PerMessageDeflate {
+        PerMessageDeflate {
+            is_server: is_server,
+            params: params,
+            compress: Compress::new(Compression::Default, false),
+            decompress: Decompress::new(false),
+            deflating: false,
+            inflating: false,
+            stream_ended: false,
+        }
+    }
+
+    fn own_no_context_takeover(&self) -> bool {
+        if self.is_server {
+            self.params.server_no_context_takeover
+        } else {
+            self.params.client_no_context_takeover
+        }
+    }
+
+    fn peer_no_context_takeover(&self) -> bool {
+        if self.is_server {
+            self.params.client_no_context_takeover
+        } else {
+            self.params.server_no_context_takeover
+        }
+    }
+
+    fn own_max_window_bits(&self) -> u8 {
+        let bits = if self.is_server {
+            self.params.server_max_window_bits
+        } else {
+            self.params.client_max_window_bits
+        };
+        bits.unwrap_or(MAX_WINDOW_BITS)
+    }
+
+    fn deflate(&mut self, input: &[u8], is_final: bool) -> io::Result<Vec<u8>> {
+        let mut input = input;
+        let mut output = Vec::with_capacity(input.len() + 16);
+        loop {
+            let before = self.compress.total_in();
+            self.compress.compress_vec(input, &mut output, Flush::Sync);
+            input = &input[(self.compress.total_in() - before) as usize..];
+            // A sync flush is complete once it leaves room in the output
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            let len = output.len();
+            output.reserve(len + 16);
+        }
+        if is_final {
+            if !output.ends_with(&TRAILER) {
+                return Err(compression_error());
+            }
+            let len = output.len() - TRAILER.len();
+            output.truncate(len);
+            if self.own_no_context_takeover() {
+                self.compress.reset();
+            }
+        }
+        Ok(output)
+    }
+
+    /// Inflates `input` into `output`, failing as soon as `output` would
+    /// grow beyond `max_len`. A final DEFLATE block ends the message: the
+    /// decompressor starts over and any input after the block is ignored.
+    fn inflate(&mut self, input: &[u8], output: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+        let mut input = input;
+        while !self.stream_ended {
+            let len = output.len();
+            if len > max_len {
+                return Err(WebSocketError::PayloadTooLarge.into());
+            }
+            // Never room for more than one byte over the limit
+            let room = cmp::max(len, input.len() * 2) + 64;
+            output.reserve_exact(cmp::min(room, (max_len - len).saturating_add(1)));
+            let before = (self.decompress.total_in(), output.len());
+            let status = try!(self.decompress
+                .decompress_vec(input, output, Flush::Sync)
+                .map_err(|_| compression_error()));
+            let consumed = (self.decompress.total_in() - before.0) as usize;
+            input = &input[consumed..];
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                self.stream_ended = true;
+            } else if input.is_empty() && output.len() < output.capacity() {
+                break;
+            } else if consumed == 0 && output.len() == before.1 {
+                return Err(compression_error());
+            }
+        }
+        if output.len() > max_len {
+            return Err(WebSocketError::PayloadTooLarge.into());
+        }
+        Ok(())
+    }
+
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn rsv_bits(&self) -> u8 {
+        RSV1
+    }
+
+    /// Compresses the payload of an outgoing data frame. Messages are only
+    /// compressed if our side may use the full window, everything else is
+    /// sent as is.
+    fn encode_frame(&mut self, mut frame: Frame) -> io::Result<Frame> {
+        match frame.header.opcode {
+            Opcode::Text | Opcode::Binary => {
+                self.deflating = self.own_max_window_bits() == MAX_WINDOW_BITS;
+                frame.header.rsv1 = self.deflating;
+            }
+            Opcode::Continuation => {}
+            _ => return Ok(frame),
+        }
+        if !self.deflating {
+            return Ok(frame);
+        }
+
+        let compressed = try!(self.deflate(&frame.payload, frame.header.is_final));
+        if frame.header.is_final {
+            self.deflating = false;
+        }
+        frame.header.payload_len = compressed.len();
+        frame.payload = Bytes::from(compressed);
+        Ok(frame)
+    }
+
+    /// Decompresses the payload of an incoming data frame and clears RSV1.
+    fn decode_frame(&mut self, mut frame: Frame, max_payload_len: usize) -> io::Result<Frame> {
+        match frame.header.opcode {
+            Opcode::Text | Opcode::Binary => {
+                self.inflating = frame.header.rsv1;
+                self.stream_ended = false;
+            }
+            _ if frame.header.rsv1 => {
+                return Err(WebSocketError::ReservedBitsSet.into());
+            }
+            Opcode::Continuation => {}
+            _ => return Ok(frame),
+        }
+        if !self.inflating {
+            return Ok(frame);
+        }
+
+        let mut payload = Vec::new();
+        try!(self.inflate(&frame.payload, &mut payload, max_payload_len));
+        if frame.header.is_final {
+            try!(self.inflate(&TRAILER, &mut payload, max_payload_len));
+            self.inflating = false;
+            if self.peer_no_context_takeover() && !self.stream_ended {
+                self.decompress.reset(false);
+            }
+        }
+        frame.header.rsv1 = false;
+        frame.header.payload_len = payload.len();
+        frame.payload = Bytes::from(payload);
+        Ok(frame)
+    }
+}
+
+impl ExtensionNegotiator for DeflateConfig {
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn offer(&self) -> String {
+        self.to_header()
+    }
+
+    fn accept_offer(&self, params: &ExtensionParams) -> Option<(Box<Extension>, String)> {
+        let offer = match parse_params(params) {
+            Some(offer) => offer,
+            None => return None,
+        };
+        let params = DeflateConfig {
+            server_no_context_takeover: offer.server_no_context_takeover ||
+                                        self.server_no_context_takeover,
+            client_no_context_takeover: offer.client_no_context_takeover ||
+                                        self.client_no_context_takeover,
+            server_max_window_bits: match (offer.server_max_window_bits,
+                                           self.server_max_window_bits) {
+                (Some(offered), Some(wanted)) => Some(cmp::min(offered, wanted)),
+                (offered, wanted) => offered.or(wanted),
+            },
+            // Only allowed in the response if the client announced support
+            client_max_window_bits: match offer.client_max_window_bits {
+                Some(offered) => self.client_max_window_bits.map(|b| cmp::min(b, offered)),
+                None => None,
+            },
+        };
+        let response = params.to_header();
+        Some((Box::new(PerMessageDeflate::new(true, params)), response))
+    }
+
+    fn accept_response(&self, params: &ExtensionParams) -> io::Result<Box<Extension>> {
+        let params = match parse_params(params) {
+            Some(params) => params,
+            None => return Err(negotiation_error("invalid permessage-deflate response")),
+        };
+        if let (Some(offered), Some(accepted)) = (self.server_max_window_bits,
+                                                 params.server_max_window_bits) {
+            if accepted > offered {
+                return Err(negotiation_error("server_max_window_bits larger than offered"));
+            }
+        }
+        match (self.client_max_window_bits, params.client_max_window_bits) {
+            (None, Some(_)) => return Err(negotiation_error("client_max_window_bits not offered")),
+            (Some(offered), Some(accepted)) if accepted > offered => {
+                return Err(negotiation_error("client_max_window_bits larger than offered"));
+            }
+            _ => {}
+        }
+        Ok(Box::new(PerMessageDeflate::new(false, params)))
+    }
+}
@@ -0,0 +1,164 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use ws_close::CloseCode;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_codes() {
+        assert_eq!(WebSocketError::InvalidOpcode(3).close_code(), CloseCode::ProtocolError);
+        assert_eq!(WebSocketError::InvalidUtf8.close_code(), CloseCode::InvalidPayload);
+        assert_eq!(WebSocketError::PayloadTooLarge.close_code(), CloseCode::TooBig);
+        let io_error = io::Error::new(io::ErrorKind::BrokenPipe, "gone");
+        assert_eq!(WebSocketError::Io(io_error).close_code(), CloseCode::InternalError);
+    }
+
+    #[test]
+    fn clone_io() {
+        let e = WebSocketError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        let e: io::Error = e.clone().into();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(e.to_string(), "gone");
+    }
+
+    #[test]
+    fn through_io_error() {
+        let e: io::Error = WebSocketError::ReservedBitsSet.into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        match WebSocketError::from_io(&e) {
+            Some(&WebSocketError::ReservedBitsSet) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let e: io::Error = WebSocketError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "gone"))
+            .into();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert!(WebSocketError::from_io(&e).is_none());
+    }
+}
+
+/// Why a connection failed. The codecs return these wrapped in an
+/// `io::Error`, use `WebSocketError::from_io` to get them back.
+#[derive(Debug)]
+pub enum WebSocketError {
+    InvalidOpcode(u8),
+    /// A frame had RSV bits set that no negotiated extension claims.
+    ReservedBitsSet,
+    ControlFrameTooLarge,
+    FragmentedControlFrame,
+    InvalidUtf8,
+    PayloadTooLarge,
+    UnmaskedClientFrame,
+    MaskedServerFrame,
+    /// Any other violation of RFC 6455.
+    ProtocolError(String),
+    /// The opening handshake failed or has not completed.
+    HandshakeFailed(String),
+    /// A frame was sent after our Close.
+    AlreadyClosed,
+    Io(io::Error),
+}
+
+impl WebSocketError {
+    /// The status code to send in the Close frame for this failure.
+    pub fn close_code(&self) -> CloseCode {
+        match *self {
+            WebSocketError::InvalidUtf8 => CloseCode::InvalidPayload,
+            WebSocketError::PayloadTooLarge => CloseCode::TooBig,
+            WebSocketError::AlreadyClosed => CloseCode::Normal,
+            WebSocketError::Io(_) => CloseCode::InternalError,
+            _ => CloseCode::ProtocolError,
+        }
+    }
+
+    /// The `WebSocketError` inside an error returned by one of the codecs,
+    /// if there is one.
+    pub fn from_io(e: &io::Error) -> Option<&WebSocketError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<WebSocketError>())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            WebSocketError::HandshakeFailed(_) |
+            WebSocketError::AlreadyClosed => io::ErrorKind::Other,
+            WebSocketError::Io(ref e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl Clone for WebSocketError {
+    /// An `Io` error is copied as its kind and message.
+    fn clone(&self) -> WebSocketError {
+        match *self {
+            WebSocketError::InvalidOpcode(opcode) => WebSocketError::InvalidOpcode(opcode),
+            WebSocketError::ReservedBitsSet => WebSocketError::ReservedBitsSet,
+            WebSocketError::ControlFrameTooLarge => WebSocketError::ControlFrameTooLarge,
+            WebSocketError::FragmentedControlFrame => WebSocketError::FragmentedControlFrame,
+            WebSocketError::InvalidUtf8 => WebSocketError::InvalidUtf8,
+            WebSocketError::PayloadTooLarge => WebSocketError::PayloadTooLarge,
+            WebSocketError::UnmaskedClientFrame => WebSocketError::UnmaskedClientFrame,
+            WebSocketError::MaskedServerFrame => WebSocketError::MaskedServerFrame,
+            WebSocketError::ProtocolError(ref reason) => {
+                WebSocketError::ProtocolError(reason.clone())
+            }
+            WebSocketError::HandshakeFailed(ref reason) => {
+                WebSocketError::HandshakeFailed(reason.clone())
+            }
+            WebSocketError::AlreadyClosed => WebSocketError::AlreadyClosed,
+            WebSocketError::Io(ref e) => WebSocketError::Io(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebSocketError::InvalidOpcode(opcode) => write!(f, "invalid opcode {}", opcode),
+            WebSocketError::ProtocolError(ref reason) => write!(f, "protocol error: {}", reason),
+            WebSocketError::HandshakeFailed(ref reason) => {
+                write!(f, "handshake failed: {}", reason)
+            }
+            WebSocketError::Io(ref e) => e.fmt(f),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn description(&self) -> &str {
+        match *self {
+            WebSocketError::InvalidOpcode(_) => "invalid opcode",
+            WebSocketError::ReservedBitsSet => "reserved bits set",
+            WebSocketError::ControlFrameTooLarge => "control frame too large",
+            WebSocketError::FragmentedControlFrame => "fragmented control frame",
+            WebSocketError::InvalidUtf8 => "invalid utf-8 in text",
+            WebSocketError::PayloadTooLarge => "payload too large",
+            WebSocketError::UnmaskedClientFrame => "unmasked frame from client",
+            WebSocketError::MaskedServerFrame => "masked frame from server",
+            WebSocketError::ProtocolError(ref reason) |
+            WebSocketError::HandshakeFailed(ref reason) => reason,
+            WebSocketError::AlreadyClosed => "close frame already sent",
+            WebSocketError::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
+
+impl From<WebSocketError> for io::Error {
+    fn from(e: WebSocketError) -> io::Error {
+        match e {
+            WebSocketError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
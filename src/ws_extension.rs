@@ -0,0 +1,341 @@
+use std::io;
+use std::sync::Arc;
+
+use ws_error::WebSocketError;
+use ws_frame::Frame;
+
+#[cfg(test)]
+mod tests {
+    use ws_close::CloseState;
+    use ws_config::Config;
+    use ws_frame::new_text_frame;
+    use ws_request::outgoing_frames;
+
+    use super::*;
+
+    struct Tag {
+        name: &'static str,
+        rsv_bits: u8,
+    }
+
+    impl Extension for Tag {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn rsv_bits(&self) -> u8 {
+            self.rsv_bits
+        }
+
+        fn encode_frame(&mut self, mut frame: Frame) -> io::Result<Frame> {
+            frame.payload.extend_from_slice(self.name.as_bytes());
+            frame.header.payload_len = frame.payload.len();
+            frame.header.set_rsv_bits(self.rsv_bits);
+            Ok(frame)
+        }
+
+        fn decode_frame(&mut self, mut frame: Frame, _: usize) -> io::Result<Frame> {
+            let len = frame.payload.len() - self.name.len();
+            assert_eq!(&frame.payload[len..], self.name.as_bytes());
+            frame.payload.truncate(len);
+            frame.header.payload_len = len;
+            frame.header.set_rsv_bits(0);
+            Ok(frame)
+        }
+    }
+
+    impl ExtensionNegotiator for Tag {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn offer(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn accept_offer(&self, params: &ExtensionParams) -> Option<(Box<Extension>, String)> {
+            if !params.is_empty() {
+                return None;
+            }
+            let tag = Tag {
+                name: self.name,
+                rsv_bits: self.rsv_bits,
+            };
+            Some((Box::new(tag), self.name.to_string()))
+        }
+
+        fn accept_response(&self, params: &ExtensionParams) -> io::Result<Box<Extension>> {
+            match self.accept_offer(params) {
+                Some((tag, _)) => Ok(tag),
+                None => Err(io::Error::new(io::ErrorKind::Other, "unexpected params")),
+            }
+        }
+    }
+
+    fn negotiators() -> Vec<Arc<ExtensionNegotiator>> {
+        vec![Arc::new(Tag {
+                 name: "x-a",
+                 rsv_bits: RSV2,
+             }),
+             Arc::new(Tag {
+                 name: "x-b",
+                 rsv_bits: RSV3,
+             }),
+             Arc::new(Tag {
+                 name: "x-c",
+                 rsv_bits: RSV3,
+             })]
+    }
+
+    #[test]
+    fn parse_extension_header() {
+        let extensions = parse_extensions("permessage-deflate; client_max_window_bits=\"10\"; \
+                                           server_no_context_takeover, x-foo");
+        assert_eq!(extensions,
+                   vec![("permessage-deflate".to_string(),
+                         vec![("client_max_window_bits".to_string(), Some("10".to_string())),
+                              ("server_no_context_takeover".to_string(), None)]),
+                        ("x-foo".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn server_negotiation() {
+        let (extensions, response) = Extensions::accept_offers(&negotiators(),
+                                                               "x-b; foo, x-unknown, x-c, x-b, x-a");
+        assert_eq!(extensions.rsv_bits(), RSV2 | RSV3);
+        // x-b was offered again after x-c claimed RSV3
+        assert_eq!(response, Some("x-c, x-a".to_string()));
+
+        let (extensions, response) = Extensions::accept_offers(&negotiators(), "x-unknown");
+        assert_eq!(extensions.rsv_bits(), 0);
+        assert_eq!(response, None);
+    }
+
+    #[test]
+    fn client_negotiation() {
+        assert_eq!(Extensions::offer(&negotiators()), Some("x-a, x-b, x-c".to_string()));
+        assert!(Extensions::accept_response(&negotiators(), "x-a, x-b").is_ok());
+        assert!(Extensions::accept_response(&negotiators(), "x-a, x-a").is_err());
+        assert!(Extensions::accept_response(&negotiators(), "x-b, x-c").is_err());
+        assert!(Extensions::accept_response(&negotiators(), "x-d").is_err());
+        assert!(Extensions::accept_response(&negotiators(), "x-a; foo").is_err());
+    }
+
+    #[test]
+    fn frames_pass_through_in_order() {
+        let (mut extensions, _) = Extensions::accept_offers(&negotiators(), "x-a, x-b");
+        let frame = extensions.encode_frame(new_text_frame("blub", None)).unwrap();
+        assert_eq!(frame.payload, b"blubx-ax-b".to_vec());
+        assert_eq!(frame.header.rsv_bits(), RSV3);
+
+        let mut frame = frame;
+        frame.header.set_rsv_bits(RSV2 | RSV3);
+        let frame = extensions.decode_frame(frame, ::std::usize::MAX).unwrap();
+        assert_eq!(frame.payload, b"blub".to_vec());
+    }
+
+    #[test]
+    fn fragments_pass_through_one_by_one() {
+        let (mut extensions, _) = Extensions::accept_offers(&negotiators(), "x-a");
+        let mut config = Config::new();
+        config.max_outgoing_frame_size(2);
+        let frames = outgoing_frames(new_text_frame("blub", None),
+                                     &config,
+                                     &mut extensions,
+                                     &mut CloseState::Open)
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        let mut data = Vec::new();
+        for frame in frames {
+            assert_eq!(frame.header.rsv_bits(), RSV2);
+            let frame = extensions.decode_frame(frame, ::std::usize::MAX).unwrap();
+            data.extend_from_slice(&frame.payload);
+        }
+        assert_eq!(data, b"blub".to_vec());
+    }
+
+    #[test]
+    fn unowned_rsv_bits() {
+        let (mut extensions, _) = Extensions::accept_offers(&negotiators(), "x-a");
+        let mut frame = new_text_frame("blubx-a", None);
+        frame.header.set_rsv_bits(RSV2);
+        assert!(extensions.decode_frame(frame.clone(), ::std::usize::MAX).is_ok());
+        frame.header.set_rsv_bits(RSV1);
+        assert!(extensions.decode_frame(frame, ::std::usize::MAX).is_err());
+
+        let mut extensions = Extensions::new();
+        let mut frame = new_text_frame("blub", None);
+        frame.header.set_rsv_bits(RSV3);
+        assert!(extensions.decode_frame(frame, ::std::usize::MAX).is_err());
+    }
+}
+
+pub const RSV1: u8 = 0x40;
+pub const RSV2: u8 = 0x20;
+pub const RSV3: u8 = 0x10;
+
+pub type ExtensionParams = Vec<(String, Option<String>)>;
+
+/// An extension negotiated for one connection. It lives in the codec, so it
+/// has to be `Send` for the transport to be.
+pub trait Extension: Send {
+    fn name(&self) -> &str;
+
+    /// The RSV bits (`RSV1`, `RSV2`, `RSV3`) this extension may set. Frames
+    /// with bits that no negotiated extension claims fail the connection.
+    fn rsv_bits(&self) -> u8;
+
+    /// Transforms a frame before it is written. Messages are split into
+    /// frames first, so this sees the same frames the peer decodes.
+    fn encode_frame(&mut self, frame: Frame) -> io::Result<Frame>;
+
+    /// Transforms a frame after it is read. Extensions that enlarge payloads
+    /// fail with `WebSocketError::PayloadTooLarge` as soon as the payload
+    /// would grow beyond `max_payload_len`.
+    fn decode_frame(&mut self, frame: Frame, max_payload_len: usize) -> io::Result<Frame>;
+}
+
+/// Takes part in the opening handshake on behalf of an extension and creates
+/// the `Extension` for each connection that negotiated it.
+pub trait ExtensionNegotiator: Send + Sync {
+    /// The extension token in `Sec-WebSocket-Extensions`.
+    fn name(&self) -> &str;
+
+    /// The extension and parameters a client offers.
+    fn offer(&self) -> String;
+
+    /// Answers a client's offer, returning the extension and the element to
+    /// put in the server's response, or `None` to decline the offer.
+    fn accept_offer(&self, params: &ExtensionParams) -> Option<(Box<Extension>, String)>;
+
+    /// Validates the server's response to our offer.
+    fn accept_response(&self, params: &ExtensionParams) -> io::Result<Box<Extension>>;
+}
+
+/// Parses a `Sec-WebSocket-Extensions` value into extension names and their
+/// parameters.
+pub fn parse_extensions(value: &str) -> Vec<(String, ExtensionParams)> {
+    value.split(',')
+        .filter_map(|extension| {
+            let mut parts = extension.split(';').map(str::trim);
+            let name = match parts.next() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => return None,
+            };
+            let params = parts.filter(|param| !param.is_empty())
+                .map(|param| {
+                    let mut kv = param.splitn(2, '=');
+                    let key = kv.next().unwrap_or("").trim().to_string();
+                    let value = kv.next().map(|v| v.trim().trim_matches('"').to_string());
+                    (key, value)
+                })
+                .collect();
+            Some((name, params))
+        })
+        .collect()
+}
+
+fn negotiation_error(reason: &str) -> io::Error {
+    WebSocketError::HandshakeFailed(reason.to_string()).into()
+}
+
+/// The extensions in use on one connection, in the order they were
+/// negotiated.
+pub struct Extensions {
+    extensions: Vec<Box<Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions { extensions: Vec::new() }
+    }
+
+    /// The `Sec-WebSocket-Extensions` value a client sends.
+    pub fn offer(negotiators: &[Arc<ExtensionNegotiator>]) -> Option<String> {
+        if negotiators.is_empty() {
+            return None;
+        }
+        let offers: Vec<String> = negotiators.iter().map(|n| n.offer()).collect();
+        Some(offers.join(", "))
+    }
+
+    /// Accepts offers from a client's `Sec-WebSocket-Extensions` value in the
+    /// client's order of preference, skipping offers for extensions that are
+    /// already accepted or would claim RSV bits that are already taken.
+    /// Returns the value for the response header, if any.
+    pub fn accept_offers(negotiators: &[Arc<ExtensionNegotiator>],
+                         offers: &str)
+                         -> (Extensions, Option<String>) {
+        let mut extensions = Extensions::new();
+        let mut response = Vec::new();
+        for (name, params) in parse_extensions(offers) {
+            if extensions.extensions.iter().any(|e| e.name() == name) {
+                continue;
+            }
+            let negotiator = match negotiators.iter().find(|n| n.name() == name) {
+                Some(negotiator) => negotiator,
+                None => continue,
+            };
+            if let Some((extension, element)) = negotiator.accept_offer(&params) {
+                if extension.rsv_bits() & extensions.rsv_bits() == 0 {
+                    extensions.extensions.push(extension);
+                    response.push(element);
+                }
+            }
+        }
+        if response.is_empty() {
+            (extensions, None)
+        } else {
+            (extensions, Some(response.join(", ")))
+        }
+    }
+
+    /// Validates a server's `Sec-WebSocket-Extensions` value against what we
+    /// offered.
+    pub fn accept_response(negotiators: &[Arc<ExtensionNegotiator>],
+                           response: &str)
+                           -> io::Result<Extensions> {
+        let mut extensions = Extensions::new();
+        for (name, params) in parse_extensions(response) {
+            if extensions.extensions.iter().any(|e| e.name() == name) {
+                return Err(negotiation_error("extension accepted twice"));
+            }
+            let extension = match negotiators.iter().find(|n| n.name() == name) {
+                Some(negotiator) => try!(negotiator.accept_response(&params)),
+                None => return Err(negotiation_error("unexpected extension in response")),
+            };
+            if extension.rsv_bits() & extensions.rsv_bits() != 0 {
+                return Err(negotiation_error("extensions claim the same RSV bits"));
+            }
+            extensions.extensions.push(extension);
+        }
+        Ok(extensions)
+    }
+
+    /// The RSV bits claimed by all negotiated extensions.
+    pub fn rsv_bits(&self) -> u8 {
+        self.extensions.iter().fold(0, |bits, e| bits | e.rsv_bits())
+    }
+
+    pub fn encode_frame(&mut self, frame: Frame) -> io::Result<Frame> {
+        let mut frame = frame;
+        for extension in self.extensions.iter_mut() {
+            frame = try!(extension.encode_frame(frame));
+        }
+        Ok(frame)
+    }
+
+    /// Fails on RSV bits no extension claims, then undoes the extensions'
+    /// transforms in reverse order. See `Extension::decode_frame` for
+    /// `max_payload_len`.
+    pub fn decode_frame(&mut self, frame: Frame, max_payload_len: usize) -> io::Result<Frame> {
+        if frame.header.rsv_bits() & !self.rsv_bits() != 0 {
+            return Err(WebSocketError::ReservedBitsSet.into());
+        }
+        let mut frame = frame;
+        for extension in self.extensions.iter_mut().rev() {
+            frame = try!(extension.decode_frame(frame, max_payload_len));
+        }
+        Ok(frame)
+    }
+}
@@ -1,9 +1,98 @@
+use std::cmp;
 use std::string;
 
+use bytes::Bytes;
+
+use ws_close::{CloseCode, close_payload};
+use ws_error::WebSocketError;
+use ws_extension::{RSV1, RSV2, RSV3};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_slow(masking_key: u32, bytes: &mut [u8]) {
+        let key = [(masking_key >> 24) as u8,
+                   (masking_key >> 16) as u8,
+                   (masking_key >> 8) as u8,
+                   masking_key as u8];
+        mask_bytewise(&key, 0, bytes);
+    }
+
+    #[test]
+    fn mask_matches_bytewise() {
+        let data: Vec<u8> = (0..100u8).collect();
+        // Every alignment of the start and every length around a few words
+        for start in 0..16 {
+            for len in 0..data.len() - start {
+                let mut fast = data.clone();
+                let mut slow = data.clone();
+                mask_in_place(0x11223344, &mut fast[start..start + len]);
+                mask_slow(0x11223344, &mut slow[start..start + len]);
+                assert_eq!(fast, slow, "start {} len {}", start, len);
+            }
+        }
+    }
+
+    #[test]
+    fn mask_rfc_example() {
+        // RFC 6455 §5.7, "Hello" masked with 37 fa 21 3d
+        let mut data = b"Hello".to_vec();
+        mask_in_place(0x37fa213d, &mut data);
+        assert_eq!(data, vec![0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+    }
+
+    #[test]
+    fn fragments() {
+        let frames = fragment(new_text_frame("blub!", None), 2);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].header.opcode, Opcode::Text);
+        assert!(frames[1..].iter().all(|f| f.header.opcode == Opcode::Continuation));
+        assert!(frames[2].header.is_final && !frames[1].header.is_final);
+    }
+
+    #[test]
+    #[should_panic(expected = "masked frames can't be fragmented")]
+    fn fragment_masked() {
+        // Even one that fits, so nothing depends on the payload length
+        fragment(new_text_frame("blub", Some(0x11121314)), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame size must be positive")]
+    fn fragment_zero_size() {
+        fragment(new_text_frame("blub", None), 0);
+    }
+
+    #[test]
+    fn control_frame_rules() {
+        assert!(new_ping_frame(&[0; 125], None).header.check_control().is_ok());
+        match new_ping_frame(&[0; 126], None).header.check_control() {
+            Err(WebSocketError::ControlFrameTooLarge) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        let mut pong = new_pong_frame(b"", None);
+        pong.header.is_final = false;
+        match pong.header.check_control() {
+            Err(WebSocketError::FragmentedControlFrame) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        // Only control frames are limited
+        let mut text = new_text_frame(&"x".repeat(126), None);
+        text.header.is_final = false;
+        assert!(text.header.check_control().is_ok());
+    }
+}
+
+/// The largest payload a Close, Ping or Pong frame may carry.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub header: Header,
-    pub payload: Vec<u8>,
+    /// Always unmasked, `header.masking_key` is only used on the wire.
+    /// Cloning a frame shares the payload instead of copying it.
+    pub payload: Bytes,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -19,35 +108,116 @@ pub enum Opcode {
 #[derive(Debug, Clone)]
 pub struct Header {
     pub is_final: bool,
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
     pub opcode: Opcode,
     pub is_masked: bool,
     pub payload_len: usize,
     pub masking_key: u32,
 }
 
+impl Header {
+    /// The RSV bits as they appear in the first byte of the frame.
+    pub fn rsv_bits(&self) -> u8 {
+        let mut bits = 0;
+        if self.rsv1 {
+            bits |= RSV1;
+        }
+        if self.rsv2 {
+            bits |= RSV2;
+        }
+        if self.rsv3 {
+            bits |= RSV3;
+        }
+        bits
+    }
+
+    pub fn set_rsv_bits(&mut self, bits: u8) {
+        self.rsv1 = bits & RSV1 != 0;
+        self.rsv2 = bits & RSV2 != 0;
+        self.rsv3 = bits & RSV3 != 0;
+    }
+
+    pub fn is_control(&self) -> bool {
+        match self.opcode {
+            Opcode::Close | Opcode::Ping | Opcode::Pong => true,
+            _ => false,
+        }
+    }
+
+    /// Checks the rules of RFC 6455 §5.5: control frames carry at most 125
+    /// payload bytes and are never fragmented.
+    pub fn check_control(&self) -> Result<(), WebSocketError> {
+        if !self.is_control() {
+            return Ok(());
+        }
+        if !self.is_final {
+            return Err(WebSocketError::FragmentedControlFrame);
+        }
+        if self.payload_len > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::ControlFrameTooLarge);
+        }
+        Ok(())
+    }
+}
+
 impl Frame {
+    /// Copies the payload into a `String`. One frame of a fragmented message
+    /// may end in the middle of a character, so prefer `MessageCodec`, which
+    /// yields whole text messages.
     pub fn payload_string(&self) -> Result<String, string::FromUtf8Error> {
-        if !self.header.is_masked {
-            return String::from_utf8(self.payload.clone())
-        }
-        String::from_utf8(mask_bytes(self.header.masking_key, &self.payload))
+        String::from_utf8(self.payload.to_vec())
+    }
+
+    /// The payload of any frame. Payloads are never masked, the decoder
+    /// unmasks them and `encode` masks them while writing.
+    pub fn unmasked_payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// Masks or unmasks `bytes` with `masking_key`, RFC 6455 §5.3.
+///
+/// Works a word at a time: after masking bytes one by one up to the first
+/// 8 byte aligned address, the key is rotated to line up with that address
+/// and XORed into whole words.
+pub fn mask_in_place(masking_key: u32, bytes: &mut [u8]) {
+    let key = [(masking_key >> 24) as u8,
+               (masking_key >> 16) as u8,
+               (masking_key >> 8) as u8,
+               masking_key as u8];
+    // Safe because any 8 bytes are a valid u64, and `align_to_mut` only
+    // hands out the aligned middle of `bytes` as words
+    let (head, words, tail) = unsafe { bytes.align_to_mut::<u64>() };
+    mask_bytewise(&key, 0, head);
+
+    let mut rotated = [0u8; 8];
+    for (i, b) in rotated.iter_mut().enumerate() {
+        *b = key[(head.len() + i) % 4];
+    }
+    // Same byte order as the payload, whatever the platform's endianness
+    let key_word = u64::from_ne_bytes(rotated);
+    // The compiler vectorizes this into 16 or 32 byte steps where it can
+    for word in words.iter_mut() {
+        *word ^= key_word;
     }
+
+    mask_bytewise(&key, head.len() + words.len() * 8, tail);
 }
 
-fn mask_bytes(masking_key: u32, bytes: &[u8]) -> Vec<u8> {
-    let mut i = 0;
-    let masking_keys = [
-        ((masking_key & 0xff000000) >> 24) as u8,
-        ((masking_key & 0x00ff0000) >> 16) as u8,
-        ((masking_key & 0x0000ff00) >> 8) as u8,
-        (masking_key & 0x000000ff) as u8,
-    ];
-    let mut masked = Vec::new();
-    masked.reserve(bytes.len());
-    for b in bytes.iter() {
-        masked.push(b ^ masking_keys[i]);
-        i = (i + 1) % 4;
+/// Masks `bytes`, which start `offset` bytes into the payload, one byte at a
+/// time.
+fn mask_bytewise(key: &[u8; 4], offset: usize, bytes: &mut [u8]) {
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b ^= key[(offset + i) % 4];
     }
+}
+
+#[cfg(test)]
+pub fn mask_bytes(masking_key: u32, bytes: &[u8]) -> Vec<u8> {
+    let mut masked = bytes.to_vec();
+    mask_in_place(masking_key, &mut masked);
     masked
 }
 
@@ -74,20 +244,77 @@ pub fn u8_to_opcode(bits: u8) -> Option<Opcode> {
     }
 }
 
-pub fn new_text_frame(text: &str, masking_key: Option<u32>) -> Frame {
-    let masked_text = match masking_key {
-        Some(masking_key) => mask_bytes(masking_key, text.as_bytes()),
-        None => text.as_bytes().to_vec(),
-    };
-
+fn new_frame(opcode: Opcode, payload: &[u8], masking_key: Option<u32>) -> Frame {
     Frame {
         header: Header {
             is_final: true,
-            opcode: Opcode::Text,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode: opcode,
             is_masked: masking_key.is_some(),
-            payload_len: text.len(),
+            payload_len: payload.len(),
             masking_key: masking_key.unwrap_or(0),
         },
-        payload: masked_text,
+        payload: Bytes::from(payload),
+    }
+}
+
+pub fn new_text_frame(text: &str, masking_key: Option<u32>) -> Frame {
+    new_frame(Opcode::Text, text.as_bytes(), masking_key)
+}
+
+pub fn new_ping_frame(payload: &[u8], masking_key: Option<u32>) -> Frame {
+    new_frame(Opcode::Ping, payload, masking_key)
+}
+
+pub fn new_pong_frame(payload: &[u8], masking_key: Option<u32>) -> Frame {
+    new_frame(Opcode::Pong, payload, masking_key)
+}
+
+pub fn new_close_frame(code: CloseCode, reason: &str, masking_key: Option<u32>) -> Frame {
+    new_frame(Opcode::Close, &close_payload(code, reason), masking_key)
+}
+
+/// Splits `frame` into frames carrying at most `max_frame_size` payload
+/// bytes each. The first frame keeps the original opcode, the rest are
+/// continuations, and only the last one inherits the original FIN bit.
+/// Control frames are never split.
+///
+/// `frame` has to be unmasked: RFC 6455 §5.3 wants a fresh masking key for
+/// every frame, so a client masks each of the split frames itself.
+///
+/// Panics if `max_frame_size` is 0 or `frame` is masked.
+pub fn fragment(frame: Frame, max_frame_size: usize) -> Vec<Frame> {
+    assert!(max_frame_size > 0, "frame size must be positive");
+    assert!(!frame.header.is_masked, "masked frames can't be fragmented");
+    if frame.header.is_control() || frame.payload.len() <= max_frame_size {
+        return vec![frame];
     }
+
+    let Frame { header, payload } = frame;
+    let chunk_count = (payload.len() + max_frame_size - 1) / max_frame_size;
+    (0..chunk_count)
+        .map(|i| {
+            let start = i * max_frame_size;
+            let chunk = payload.slice(start, cmp::min(start + max_frame_size, payload.len()));
+            Frame {
+                header: Header {
+                    is_final: header.is_final && i == chunk_count - 1,
+                    rsv1: header.rsv1,
+                    rsv2: header.rsv2,
+                    rsv3: header.rsv3,
+                    opcode: if i == 0 {
+                        header.opcode.clone()
+                    } else {
+                        Opcode::Continuation
+                    },
+                    is_masked: false,
+                    payload_len: chunk.len(),
+                    masking_key: 0,
+                },
+                payload: chunk,
+            }
+        })
+        .collect()
 }
@@ -0,0 +1,291 @@
+use std::net::SocketAddr;
+
+use base64;
+use tokio_minihttp;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(uri: &str) -> Handshake {
+        Handshake {
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            headers: vec![("Authorization".to_string(), b"Bearer abc".to_vec()),
+                          ("X-Room".to_string(), b"1".to_vec()),
+                          ("x-room".to_string(), b"2".to_vec())],
+            peer_addr: None,
+            protocol: None,
+        }
+    }
+
+    #[test]
+    fn path_and_query() {
+        let h = handshake("/ws/chat/42?token=abc&x=1");
+        assert_eq!(h.path(), "/ws/chat/42");
+        assert_eq!(h.query(), Some("token=abc&x=1"));
+
+        let h = handshake("/ws/chat/42");
+        assert_eq!(h.path(), "/ws/chat/42");
+        assert_eq!(h.query(), None);
+    }
+
+    #[test]
+    fn header_lookup() {
+        let h = handshake("/");
+        assert_eq!(h.header("authorization"), Some(&b"Bearer abc"[..]));
+        assert_eq!(h.header("X-ROOM"), Some(&b"1"[..]));
+        assert_eq!(h.header("Origin"), None);
+    }
+
+    fn upgrade() -> Handshake {
+        let mut h = handshake("/chat");
+        h.headers = vec![("Host".to_string(), b"server.example.com".to_vec()),
+                         ("Upgrade".to_string(), b"WebSocket".to_vec()),
+                         ("Connection".to_string(), b"keep-alive, Upgrade".to_vec()),
+                         ("Sec-WebSocket-Key".to_string(), b"dGhlIHNhbXBsZSBub25jZQ==".to_vec()),
+                         ("Sec-WebSocket-Version".to_string(), b"13".to_vec())];
+        h
+    }
+
+    fn without(mut h: Handshake, name: &str) -> Handshake {
+        h.headers.retain(|&(ref header, _)| header != name);
+        h
+    }
+
+    fn with(h: Handshake, name: &str, value: &str) -> Handshake {
+        let mut h = without(h, name);
+        h.headers.push((name.to_string(), value.as_bytes().to_vec()));
+        h
+    }
+
+    fn status(result: Result<String, HandshakeResponse>) -> u32 {
+        match result {
+            Ok(_) => 101,
+            Err(res) => res.status,
+        }
+    }
+
+    #[test]
+    fn valid_upgrade() {
+        assert_eq!(validate(&upgrade(), 1).unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+    }
+
+    #[test]
+    fn invalid_upgrades() {
+        let mut post = upgrade();
+        post.method = "POST".to_string();
+        assert_eq!(status(validate(&post, 1)), 400);
+        assert_eq!(status(validate(&upgrade(), 0)), 400);
+        assert_eq!(status(validate(&without(upgrade(), "Host"), 1)), 400);
+        assert_eq!(status(validate(&without(upgrade(), "Upgrade"), 1)), 400);
+        assert_eq!(status(validate(&with(upgrade(), "Upgrade", "h2c"), 1)), 400);
+        assert_eq!(status(validate(&with(upgrade(), "Connection", "keep-alive"), 1)), 400);
+        assert_eq!(status(validate(&without(upgrade(), "Sec-WebSocket-Key"), 1)), 400);
+        assert_eq!(status(validate(&with(upgrade(), "Sec-WebSocket-Key", "c2hvcnQ="), 1)), 400);
+        assert_eq!(status(validate(&with(upgrade(), "Sec-WebSocket-Key", "not base64!"), 1)),
+                   400);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        match validate(&with(upgrade(), "Sec-WebSocket-Version", "8"), 1) {
+            Err(res) => {
+                assert_eq!(res.status, 426);
+                assert_eq!(res.headers,
+                           vec![("Sec-WebSocket-Version".to_string(), "13".to_string())]);
+            }
+            Ok(_) => panic!("accepted version 8"),
+        }
+        assert_eq!(status(validate(&without(upgrade(), "Sec-WebSocket-Version"), 1)), 426);
+    }
+
+    #[test]
+    fn accept_and_reject() {
+        let mut res = HandshakeResponse::accept();
+        res.header("Set-Cookie", "session=1");
+        assert!(res.is_accepted());
+        assert_eq!(res.headers, vec![("Set-Cookie".to_string(), "session=1".to_string())]);
+
+        let mut res = HandshakeResponse::reject(403, "Forbidden");
+        res.body("bad origin");
+        assert!(!res.is_accepted());
+        assert_eq!(res.status, 403);
+        assert_eq!(res.body, "bad origin");
+    }
+
+    #[test]
+    #[should_panic(expected = "a rejection needs an error status")]
+    fn reject_with_101() {
+        HandshakeResponse::reject(101, "Switching Protocols");
+    }
+}
+
+/// The HTTP request that opened a connection, passed to the application in
+/// `Request::Open`.
+///
+/// On the client side `method` and `uri` are those of the request we sent
+/// and `headers` are the headers of the server's response.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub method: String,
+    /// The request target, including the query string.
+    pub uri: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Passed to `accept_with_config`, taken from the socket by
+    /// `WebSocket::with_peer_addr`, or given to the codec with `peer_addr`.
+    pub peer_addr: Option<SocketAddr>,
+    /// The negotiated subprotocol.
+    pub protocol: Option<String>,
+}
+
+impl Handshake {
+    pub fn from_http(req: &tokio_minihttp::Request) -> Handshake {
+        Handshake {
+            method: req.method().to_string(),
+            uri: req.path().to_string(),
+            headers: req.headers()
+                .map(|(name, value)| (name.to_string(), value.to_vec()))
+                .collect(),
+            peer_addr: None,
+            protocol: None,
+        }
+    }
+
+    /// The path part of `uri`.
+    pub fn path(&self) -> &str {
+        self.uri.splitn(2, '?').next().unwrap_or("")
+    }
+
+    /// The query string of `uri`, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.uri.splitn(2, '?').nth(1)
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|&&(ref header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+    }
+}
+
+/// What a `Config::handshake_hook` decided about an opening handshake:
+/// either accept it, with extra headers for the 101 response, or reject it
+/// with an HTTP error response.
+#[derive(Debug, Clone)]
+pub struct HandshakeResponse {
+    pub status: u32,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Ignored when accepting.
+    pub body: String,
+    accepted: bool,
+}
+
+impl HandshakeResponse {
+    pub fn accept() -> HandshakeResponse {
+        HandshakeResponse {
+            status: 101,
+            reason: "Switching Protocols".to_string(),
+            headers: Vec::new(),
+            body: String::new(),
+            accepted: true,
+        }
+    }
+
+    /// Panics if `status` is informational (1xx), which would not end the
+    /// HTTP exchange.
+    pub fn reject(status: u32, reason: &str) -> HandshakeResponse {
+        assert!(status >= 200, "a rejection needs an error status");
+        HandshakeResponse {
+            status: status,
+            reason: reason.to_string(),
+            headers: Vec::new(),
+            body: String::new(),
+            accepted: false,
+        }
+    }
+
+    pub fn header(&mut self, name: &str, value: &str) -> &mut HandshakeResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(&mut self, body: &str) -> &mut HandshakeResponse {
+        self.body = body.to_string();
+        self
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.accepted
+    }
+
+    /// The HTTP response for a rejected handshake. The connection is not
+    /// reused afterwards.
+    pub fn to_http(&self) -> tokio_minihttp::Response {
+        let mut res = tokio_minihttp::Response::new();
+        res.status_code(self.status, &self.reason);
+        for &(ref name, ref value) in &self.headers {
+            res.header(name, value);
+        }
+        res.header("Connection", "close");
+        res.body(&self.body);
+        res
+    }
+}
+
+/// Whether the comma separated header `value` contains `token`, ignoring
+/// case.
+pub fn header_has_token(value: &[u8], token: &str) -> bool {
+    match ::std::str::from_utf8(value) {
+        Ok(value) => value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+        Err(_) => false,
+    }
+}
+
+fn bad_request(reason: &str) -> HandshakeResponse {
+    let mut res = HandshakeResponse::reject(400, "Bad Request");
+    res.body(reason);
+    res
+}
+
+/// Checks an upgrade request against RFC 6455 §4.2.1, returning its
+/// `Sec-WebSocket-Key` or the error response to send instead of the 101.
+/// `http_version` is the minor version of HTTP/1.x.
+pub fn validate(handshake: &Handshake, http_version: u8) -> Result<String, HandshakeResponse> {
+    if handshake.method != "GET" {
+        return Err(bad_request("upgrade requires GET"));
+    }
+    if http_version < 1 {
+        return Err(bad_request("upgrade requires HTTP/1.1"));
+    }
+    if handshake.header("Host").is_none() {
+        return Err(bad_request("missing Host"));
+    }
+    match handshake.header("Upgrade") {
+        Some(value) if header_has_token(value, "websocket") => {}
+        _ => return Err(bad_request("missing Upgrade: websocket")),
+    }
+    match handshake.header("Connection") {
+        Some(value) if header_has_token(value, "Upgrade") => {}
+        _ => return Err(bad_request("missing Connection: Upgrade")),
+    }
+    match handshake.header("Sec-WebSocket-Version") {
+        Some(b"13") => {}
+        _ => {
+            let mut res = HandshakeResponse::reject(426, "Upgrade Required");
+            res.header("Sec-WebSocket-Version", "13");
+            return Err(res);
+        }
+    }
+    let key = match handshake.header("Sec-WebSocket-Key").map(::std::str::from_utf8) {
+        Some(Ok(key)) => key.trim(),
+        _ => return Err(bad_request("missing Sec-WebSocket-Key")),
+    };
+    match base64::decode(key) {
+        Ok(ref nonce) if nonce.len() == 16 => Ok(key.to_string()),
+        _ => Err(bad_request("invalid Sec-WebSocket-Key")),
+    }
+}
@@ -0,0 +1,251 @@
+use std::io;
+
+use bytes::BytesMut;
+use tokio_io::codec::{Encoder, Decoder};
+
+use ws_request::Request;
+use ws_response::Response;
+
+#[cfg(test)]
+mod tests {
+    use ws_close::{CloseCode, CloseState};
+    use ws_config::Config;
+    use ws_error::WebSocketError;
+    use ws_extension::Extensions;
+    use ws_frame::Opcode;
+    use ws_request::IncomingFrames;
+    use ws_response;
+
+    use super::*;
+
+    struct RawCodec {
+        incoming: IncomingFrames,
+        config: Config,
+        extensions: Extensions,
+        close_state: CloseState,
+    }
+
+    impl Decoder for RawCodec {
+        type Item = Request;
+        type Error = io::Error;
+
+        fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+            self.incoming.decode(buf, &self.config, &mut self.extensions, &mut self.close_state)
+        }
+    }
+
+    impl Encoder for RawCodec {
+        type Item = Response;
+        type Error = io::Error;
+
+        fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+            ws_response::encode(msg, buf)
+        }
+    }
+
+    impl MessageFrames for RawCodec {
+        fn reassemble_messages(&mut self) {
+            self.incoming.reassemble_messages();
+        }
+    }
+
+    fn raw_codec() -> RawCodec {
+        RawCodec {
+            incoming: IncomingFrames::new(false),
+            config: Config::new(),
+            extensions: Extensions::new(),
+            close_state: CloseState::Open,
+        }
+    }
+
+    fn decode_all(data: Vec<u8>) -> io::Result<Vec<Request>> {
+        let mut codec = MessageCodec::new(raw_codec());
+        let mut buf = BytesMut::from(data);
+        let mut reqs = Vec::new();
+        while let Some(req) = try!(codec.decode(&mut buf)) {
+            reqs.push(req);
+        }
+        Ok(reqs)
+    }
+
+    #[test]
+    fn unfragmented_binary() {
+        let data = vec![0x82, 0x03, 1, 2, 3];
+        match decode_all(data).unwrap().as_slice() {
+            &[Request::Message(Message::Binary(ref data))] => assert_eq!(data, &vec![1, 2, 3]),
+            e => panic!("unexpected requests: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn fragmented_text_with_ping() {
+        let data = vec![0x01, 0x03, b'H', b'e', b'l', // non-fin text "Hel"
+                        0x89, 0x01, 0x2a, // fin ping
+                        0x00, 0x01, b'l', // non-fin continuation "l"
+                        0x80, 0x01, b'o']; // fin continuation "o"
+        let reqs = decode_all(data).unwrap();
+        assert_eq!(reqs.len(), 2);
+        match reqs[0] {
+            Request::Frame(ref frame) => assert_eq!(frame.header.opcode, Opcode::Ping),
+            ref e => panic!("expected ping: {:?}", e),
+        }
+        match reqs[1] {
+            Request::Message(Message::Text(ref text)) => assert_eq!(text, "Hello"),
+            ref e => panic!("expected text: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn wrapped_in_middle_of_text() {
+        let mut codec = raw_codec();
+        // The first half of "é" arrives before reassembly is turned on
+        let mut buf = BytesMut::from(vec![0x01, 0x01, 0xc3]);
+        assert!(match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(_))) => true,
+            _ => false,
+        });
+        let mut codec = MessageCodec::new(codec);
+        let mut buf = BytesMut::from(vec![0x80, 0x01, 0xa9, // second half
+                                          0x81, 0x01, b'x']);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(ref frame))) => {
+                assert_eq!(frame.header.opcode, Opcode::Continuation)
+            }
+            e => panic!("expected the continuation frame: {:?}", e),
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Message(Message::Text(ref text)))) => assert_eq!(text, "x"),
+            e => panic!("expected text: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn wrapped_in_middle_of_binary() {
+        let mut codec = raw_codec();
+        let mut buf = BytesMut::from(vec![0x02, 0x01, 1]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let mut codec = MessageCodec::new(codec);
+        // Not a truncated message, but the frame itself
+        let mut buf = BytesMut::from(vec![0x80, 0x01, 2,
+                                          0x82, 0x01, 3]);
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Frame(ref frame))) => assert_eq!(frame.payload, vec![2]),
+            e => panic!("expected the continuation frame: {:?}", e),
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some(Request::Message(Message::Binary(ref data)))) => assert_eq!(data, &vec![3]),
+            e => panic!("expected binary: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn continuation_without_message() {
+        let data = vec![0x80, 0x01, b'x'];
+        assert!(decode_all(data).is_err());
+    }
+
+    #[test]
+    fn message_inside_fragmented_message() {
+        let data = vec![0x02, 0x01, 1, // non-fin binary
+                        0x81, 0x01, b'x']; // fin text
+        assert!(decode_all(data).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_text() {
+        let data = vec![0x01, 0x01, 0xc3, // first half of "é"
+                        0x80, 0x01, 0x28]; // not a continuation byte
+        assert!(decode_all(data).is_err());
+    }
+
+    #[test]
+    fn character_split_across_frames() {
+        let data = vec![0x01, 0x01, 0xc3, // first half of "é"
+                        0x80, 0x01, 0xa9]; // second half
+        match decode_all(data).unwrap().as_slice() {
+            &[Request::Message(Message::Text(ref text))] => assert_eq!(text, "é"),
+            e => panic!("unexpected requests: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_before_final_frame() {
+        let mut codec = MessageCodec::new(raw_codec());
+        let mut buf = BytesMut::from(vec![0x01, 0x02, b'a', 0xff]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(WebSocketError::from_io(&err).unwrap().close_code(),
+                   CloseCode::InvalidPayload);
+    }
+}
+
+/// A complete data message, reassembled from one or more frames.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Frame codecs that can yield whole messages instead of data frames, which
+/// is what `MessageCodec` asks them for.
+pub trait MessageFrames {
+    /// From the next data message on, yield each one as a `Request::Message`
+    /// once its final frame has arrived. The rest of a message that is in
+    /// progress when this is called still arrives as `Request::Frame`s,
+    /// since its earlier frames are gone.
+    fn reassemble_messages(&mut self);
+}
+
+/// Wraps a frame codec and buffers fragmented messages until their final
+/// frame arrives. Complete messages are yielded as `Request::Message`,
+/// control frames are passed through as soon as they are decoded.
+///
+/// The inner codec does the buffering itself, with the same state it checks
+/// fragmentation and UTF-8 with.
+pub struct MessageCodec<C> {
+    inner: C,
+}
+
+impl<C: MessageFrames> MessageCodec<C> {
+    /// Reassembly starts with the next data message `inner` decodes, see
+    /// `MessageFrames::reassemble_messages`.
+    pub fn new(mut inner: C) -> MessageCodec<C> {
+        inner.reassemble_messages();
+        MessageCodec { inner: inner }
+    }
+}
+
+impl<C> MessageCodec<C> {
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C> Decoder for MessageCodec<C>
+    where C: Decoder<Item = Request, Error = io::Error>
+{
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        self.inner.decode(buf)
+    }
+}
+
+impl<C> Encoder for MessageCodec<C>
+    where C: Encoder<Item = Response, Error = io::Error>
+{
+    type Item = Response;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+        self.inner.encode(msg, buf)
+    }
+}
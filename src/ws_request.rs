@@ -2,13 +2,21 @@ use std::io;
 
 use bytes::{BytesMut, BigEndian, ByteOrder};
 
-use ws_frame::{Frame, Header, u8_to_opcode};
+use ws_close::{CloseCode, CloseState, parse_close_payload};
+use ws_config::Config;
+use ws_error::WebSocketError;
+use ws_extension::Extensions;
+use ws_frame::{Frame, Header, Opcode, fragment, mask_in_place, u8_to_opcode};
+use ws_handshake::Handshake;
+use ws_message::Message;
+use ws_utf8::{Utf8Validator, check_text};
 
 #[cfg(test)]
 mod tests {
     extern crate tokio_core;
 
-    use ws_frame::Opcode;
+    use ws_frame::{mask_bytes, new_ping_frame, new_pong_frame, new_text_frame};
+    use ws_response::encode;
     use super::*;
 
 
@@ -72,10 +80,8 @@ mod tests {
         assert_eq!(req.header.opcode, Opcode::Continuation);
         assert!(req.header.is_masked);
         assert_eq!(req.header.payload_len, 256);
-        assert_eq!(req.payload.len(), 256);
-        for i in 0..256 {
-            assert_eq!(payload[i], req.payload[i]);
-        }
+        assert_eq!(req.header.masking_key, 0x11121314);
+        assert_eq!(req.payload, mask_bytes(0x11121314, &payload));
     }
 
     #[test]
@@ -105,12 +111,207 @@ mod tests {
         assert_eq!(req.header.opcode, Opcode::Continuation);
         assert!(req.header.is_masked);
         assert_eq!(req.header.payload_len, 65536);
-        assert_eq!(req.payload.len(), 65536);
-        for i in 0..65536 {
-            assert_eq!(payload[i], req.payload[i]);
+        assert_eq!(req.header.masking_key, 0x11121314);
+        assert_eq!(req.payload, mask_bytes(0x11121314, &payload));
+    }
+
+    #[test]
+    fn fin_rsv_text_unmasked_empty() {
+        let data = vec![0xf1u8, 0x00u8];
+        let mut buf = BytesMut::from(data);
+        let req = match decode(&mut buf) {
+            Ok(Some(Request::Frame(req))) => req,
+            _ => panic!("decode failed"),
+        };
+
+        assert!(req.header.rsv1);
+        assert!(req.header.rsv2);
+        assert!(req.header.rsv3);
+        assert_eq!(req.header.opcode, Opcode::Text);
+    }
+
+    fn frame_stream(frames: Vec<Frame>) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(0);
+        for frame in frames {
+            encode(frame, &mut buf).unwrap();
+        }
+        buf
+    }
+
+    // Frames with 7 and 16 bit lengths, masked and unmasked
+    fn small_frames() -> BytesMut {
+        frame_stream(vec![new_text_frame("", None),
+                          new_text_frame("blub", Some(0x01020304)),
+                          new_ping_frame(&[7; 125], None),
+                          new_pong_frame(&[8; 125], Some(0x11121314)),
+                          new_text_frame(&"a".repeat(126), None),
+                          new_text_frame(&"b".repeat(300), Some(0x21222324))])
+    }
+
+    // Frames with 64 bit lengths
+    fn large_frames() -> BytesMut {
+        frame_stream(vec![new_text_frame(&"c".repeat(65536), None),
+                          new_text_frame(&"d".repeat(65537), Some(0x31323334))])
+    }
+
+    fn decode_all(buf: &mut BytesMut, frames: &mut Vec<String>) {
+        while let Some(req) = decode(buf).unwrap() {
+            frames.push(format!("{:?}", req));
+        }
+    }
+
+    fn decode_split(stream: &BytesMut, splits: &[usize]) -> Vec<String> {
+        let mut frames = Vec::new();
+        let mut buf = BytesMut::with_capacity(0);
+        let mut start = 0;
+        for &end in splits.iter().chain(Some(stream.len()).iter()) {
+            buf.extend_from_slice(&stream[start..end]);
+            decode_all(&mut buf, &mut frames);
+            start = end;
+        }
+        assert!(buf.is_empty());
+        frames
+    }
+
+    #[test]
+    fn split_at_every_offset() {
+        let stream = small_frames();
+        let expected = decode_split(&stream, &[]);
+        assert_eq!(expected.len(), 6);
+        for offset in 0..stream.len() + 1 {
+            assert_eq!(decode_split(&stream, &[offset]), expected, "split at {}", offset);
+        }
+
+        // Every offset in the headers and around the frame boundary
+        let stream = large_frames();
+        let expected = decode_split(&stream, &[]);
+        assert_eq!(expected.len(), 2);
+        let second = 10 + 65536;
+        let offsets = (0..20).chain(second - 5..second + 20).chain(Some(stream.len()));
+        for offset in offsets {
+            assert_eq!(decode_split(&stream, &[offset]), expected, "split at {}", offset);
+        }
+    }
+
+    #[test]
+    fn one_byte_at_a_time() {
+        for stream in vec![small_frames(), large_frames()] {
+            let expected = decode_split(&stream, &[]);
+            let splits: Vec<usize> = (1..stream.len()).collect();
+            assert_eq!(decode_split(&stream, &splits), expected);
+        }
+    }
+
+    #[test]
+    fn payload_shared_with_clones() {
+        let mut buf = BytesMut::from(vec![0x82, 0x84, 1, 2, 3, 4, 1, 2, 3, 4]);
+        let frame = match decode(&mut buf) {
+            Ok(Some(Request::Frame(frame))) => frame,
+            e => panic!("decode failed: {:?}", e),
+        };
+        assert_eq!(frame.payload, vec![0u8; 4]);
+        assert_eq!(frame.clone().payload.as_ptr(), frame.payload.as_ptr());
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        let mut buf = BytesMut::from(vec![0x82, 0x7e, 0x01, 0x00]);
+        let err = decode_with_limit(&mut buf, 255).unwrap_err();
+        match WebSocketError::from_io(&err) {
+            Some(&WebSocketError::PayloadTooLarge) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let mut buf = BytesMut::from(vec![0x82, 0x7e, 0x01, 0x00]);
+        assert!(decode_with_limit(&mut buf, 256).unwrap().is_none());
+    }
+
+    #[test]
+    fn length_msb_set() {
+        let mut buf = BytesMut::from(vec![0x82, 0x7f, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        let err = decode(&mut buf).unwrap_err();
+        match WebSocketError::from_io(&err) {
+            Some(&WebSocketError::ProtocolError(_)) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let mut len = 0;
+        let mut first = new_text_frame("blub", None);
+        first.header.is_final = false;
+        let mut rest = new_text_frame("blub", None);
+        rest.header.opcode = Opcode::Continuation;
+        assert!(check_message_size(&first, &mut len, 8).is_ok());
+        assert!(check_message_size(&new_ping_frame(b"ping", None), &mut len, 8).is_ok());
+        assert!(check_message_size(&rest, &mut len, 8).is_ok());
+        assert_eq!(len, 8);
+        assert!(check_message_size(&rest, &mut len, 8).is_err());
+        // A new message starts counting from zero
+        assert!(check_message_size(&first, &mut len, 8).is_ok());
+    }
+
+    #[test]
+    fn invalid_control_frames() {
+        // Ping without FIN
+        let mut buf = BytesMut::from(vec![0x09, 0x00]);
+        match WebSocketError::from_io(&decode(&mut buf).unwrap_err()) {
+            Some(&WebSocketError::FragmentedControlFrame) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // Close with a 126 byte payload, rejected before the payload arrives
+        let mut buf = BytesMut::from(vec![0x88, 0x7e, 0x00, 0x7e]);
+        match WebSocketError::from_io(&decode(&mut buf).unwrap_err()) {
+            Some(&WebSocketError::ControlFrameTooLarge) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let mut buf = BytesMut::from(vec![0x8a, 0x7d]);
+        buf.extend_from_slice(&[0; 125]);
+        assert!(decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut fragmentation = Fragmentation::new();
+        let mut first = new_text_frame("a", None);
+        first.header.is_final = false;
+        let mut middle = new_text_frame("b", None);
+        middle.header.opcode = Opcode::Continuation;
+        middle.header.is_final = false;
+        let mut last = middle.clone();
+        last.header.is_final = true;
+        let ping = new_ping_frame(b"", None);
+
+        assert_eq!(fragmentation.check(&first).unwrap(), Some(Opcode::Text));
+        assert_eq!(fragmentation.check(&ping).unwrap(), None);
+        assert_eq!(fragmentation.check(&middle).unwrap(), Some(Opcode::Text));
+        assert_eq!(fragmentation.check(&ping).unwrap(), None);
+        assert_eq!(fragmentation.check(&last).unwrap(), Some(Opcode::Text));
+        assert!(fragmentation.check(&last).is_err());
+        assert!(fragmentation.check(&new_text_frame("c", None)).is_ok());
+    }
+
+    #[test]
+    fn continuation_without_message() {
+        let mut frame = new_text_frame("a", None);
+        frame.header.opcode = Opcode::Continuation;
+        let err = Fragmentation::new().check(&frame).unwrap_err();
+        match WebSocketError::from_io(&err) {
+            Some(&WebSocketError::ProtocolError(_)) => {}
+            e => panic!("unexpected error: {:?}", e),
         }
     }
 
+    #[test]
+    fn message_inside_fragmented_message() {
+        let mut fragmentation = Fragmentation::new();
+        let mut binary = new_text_frame("a", None);
+        binary.header.opcode = Opcode::Binary;
+        binary.header.is_final = false;
+        assert!(fragmentation.check(&binary).is_ok());
+        assert!(fragmentation.check(&new_text_frame("b", None)).is_err());
+    }
+
     #[test]
     fn afl_crash_0() {
         let data = vec![0x12, 0xff, 0xff, 0xff, 0x7f, 0x01, 0x06, 0xff, 0x7f, 0x00];
@@ -178,8 +379,21 @@ mod tests {
 
 #[derive(Debug)]
 pub enum Request {
-    Open(),
+    /// The opening handshake completed.
+    Open(Handshake),
+    /// The opening handshake was rejected. The response to this request is
+    /// replaced by the rejection. After that, reading fails as soon as the
+    /// peer sends more or closes, and nothing more can be written.
+    Rejected(Handshake),
+    /// A plain HTTP request, answered by the `Config::http_handler`. The
+    /// response to this request is replaced by the handler's, after which
+    /// the next HTTP request is read.
+    Http(Handshake),
     Frame(Frame),
+    Message(Message),
+    /// The peer sent a Close frame, with its status code and reason if it
+    /// had any. Invalid Close frames fail decoding instead.
+    Close(Option<(CloseCode, String)>),
 }
 
 enum ParseResult<T> {
@@ -192,64 +406,323 @@ fn parse_header(buf: &mut BytesMut) -> io::Result<ParseResult<Header>> {
         return Ok(ParseResult::Partial);
     }
     let is_final = buf[0] & 0x80 > 0;
+    let rsv1 = buf[0] & 0x40 > 0;
+    let rsv2 = buf[0] & 0x20 > 0;
+    let rsv3 = buf[0] & 0x10 > 0;
     let opcode = match u8_to_opcode(buf[0] & 0x0f) {
         Some(op) => op,
-        None => return Err(io::Error::new(io::ErrorKind::Other, "invalid opcode")),
+        None => return Err(WebSocketError::InvalidOpcode(buf[0] & 0x0f).into()),
     };
     let is_masked = buf[1] & 0x80 > 0;
     let (payload_len, buf_offset) = match buf[1] & 0x7f {
         126 => {
             if buf.len() < 4 {
-                return Err(io::Error::new(io::ErrorKind::Other, "not enough bytes"));
+                return Ok(ParseResult::Partial);
             }
             let len = BigEndian::read_u16(&buf[2..]) as usize;
             (len, 4)
         }
         127 => {
-            if buf.len() < 6 {
-                return Err(io::Error::new(io::ErrorKind::Other, "not enough bytes"));
+            if buf.len() < 10 {
+                return Ok(ParseResult::Partial);
+            }
+            let len = BigEndian::read_u64(&buf[2..]);
+            if len & (1 << 63) != 0 {
+                let reason = "most significant bit of payload length set".to_string();
+                return Err(WebSocketError::ProtocolError(reason).into());
             }
-            let len = BigEndian::read_u64(&buf[2..]) as usize;
-            (len, 10)
+            if len > ::std::usize::MAX as u64 {
+                return Err(WebSocketError::PayloadTooLarge.into());
+            }
+            (len as usize, 10)
         }
         x => (x as usize, 2),
     };
 
     let (masking_key, buf_offset) = if is_masked {
         if buf.len() < buf_offset + 4 {
-            return Err(io::Error::new(io::ErrorKind::Other, "not enough bytes"));
+            return Ok(ParseResult::Partial);
         }
         (BigEndian::read_u32(&buf[buf_offset..]), buf_offset + 4)
     } else {
         (0, buf_offset)
     };
 
-    Ok(ParseResult::Complete(Header {
-                                 is_final: is_final,
-                                 opcode: opcode,
-                                 is_masked: is_masked,
-                                 payload_len: payload_len,
-                                 masking_key: masking_key,
-                             },
-                             buf_offset))
+    let header = Header {
+        is_final: is_final,
+        rsv1: rsv1,
+        rsv2: rsv2,
+        rsv3: rsv3,
+        opcode: opcode,
+        is_masked: is_masked,
+        payload_len: payload_len,
+        masking_key: masking_key,
+    };
+    try!(header.check_control());
+    Ok(ParseResult::Complete(header, buf_offset))
 }
 
 pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
+    decode_with_limit(buf, ::std::usize::MAX)
+}
+
+/// Like `decode`, but fails with `PayloadTooLarge` as soon as a header
+/// announces a payload longer than `max_frame_size`.
+pub fn decode_with_limit(buf: &mut BytesMut,
+                         max_frame_size: usize)
+                         -> io::Result<Option<Request>> {
     // This is after the successful upgrade
     // Parse header
     let (header, offset) = match try!(parse_header(buf)) {
         ParseResult::Complete(h, offset) => (h, offset),
         ParseResult::Partial => return Ok(None),
     };
-    if header.payload_len + offset > buf.len() {
+    if header.payload_len > max_frame_size {
+        return Err(WebSocketError::PayloadTooLarge.into());
+    }
+    if header.payload_len > buf.len() - offset {
         return Ok(None);
     }
     // Discard header data
     buf.split_to(offset);
-    let payload = buf.split_to(header.payload_len).to_vec();
+    let mut payload = buf.split_to(header.payload_len);
+    if header.is_masked {
+        mask_in_place(header.masking_key, &mut payload);
+    }
+    let payload = payload.freeze();
 
     Ok(Some(Request::Frame(Frame {
         header: header,
         payload: payload,
     })))
 }
+
+/// Tracks the data message in progress, RFC 6455 §5.4. Control frames may
+/// be interleaved, but a new message may not start before the previous one
+/// finishes and a continuation needs a message to continue.
+#[derive(Debug)]
+pub struct Fragmentation {
+    // Opcode of a message whose final frame has not arrived yet
+    opcode: Option<Opcode>,
+}
+
+impl Fragmentation {
+    pub fn new() -> Fragmentation {
+        Fragmentation { opcode: None }
+    }
+
+    /// Checks that `frame` may follow the frames before it, and returns the
+    /// opcode of the message it belongs to, or `None` for a control frame.
+    pub fn check(&mut self, frame: &Frame) -> io::Result<Option<Opcode>> {
+        match frame.header.opcode {
+            Opcode::Text | Opcode::Binary => {
+                if self.opcode.is_some() {
+                    let reason = "new message before previous message finished".to_string();
+                    return Err(WebSocketError::ProtocolError(reason).into());
+                }
+                if !frame.header.is_final {
+                    self.opcode = Some(frame.header.opcode.clone());
+                }
+                Ok(Some(frame.header.opcode.clone()))
+            }
+            Opcode::Continuation => {
+                let opcode = if frame.header.is_final {
+                    self.opcode.take()
+                } else {
+                    self.opcode.clone()
+                };
+                match opcode {
+                    Some(opcode) => Ok(Some(opcode)),
+                    None => {
+                        let reason = "continuation frame without message".to_string();
+                        Err(WebSocketError::ProtocolError(reason).into())
+                    }
+                }
+            }
+            Opcode::Close | Opcode::Ping | Opcode::Pong => Ok(None),
+        }
+    }
+}
+
+/// Everything an open connection checks incoming frames for, in one place:
+/// masking (RFC 6455 §5.1), fragmentation, the size limits, UTF-8 in text
+/// messages and the close handshake. Extensions decode each frame along the
+/// way.
+pub struct IncomingFrames {
+    // Whether the peer has to mask its frames, which only clients do
+    masked: bool,
+    fragmentation: Fragmentation,
+    // Length of the incoming message so far
+    message_len: usize,
+    // Validation state of the incoming text message, if one is in progress
+    text: Option<Utf8Validator>,
+    // Whether data messages are reassembled, starting with the next one
+    reassemble: bool,
+    // Payload of the incoming message so far, if it is reassembled. Only
+    // created by the first frame of a message, like the text validator.
+    message: Option<Vec<u8>>,
+    // The error that failed the connection, returned again by every later call
+    failed: Option<WebSocketError>,
+}
+
+impl IncomingFrames {
+    /// `masked` is true on the server, where every incoming frame has to be
+    /// masked, and false on the client, where none may be.
+    pub fn new(masked: bool) -> IncomingFrames {
+        IncomingFrames {
+            masked: masked,
+            fragmentation: Fragmentation::new(),
+            message_len: 0,
+            text: None,
+            reassemble: false,
+            message: None,
+            failed: None,
+        }
+    }
+
+    /// Makes `decode` buffer the frames of each data message and yield the
+    /// whole message as a `Request::Message` once its final frame arrives.
+    /// Takes effect with the next Text or Binary frame, a message that is in
+    /// progress is still passed on frame by frame.
+    pub fn reassemble_messages(&mut self) {
+        self.reassemble = true;
+    }
+
+    /// Decodes the next frame from `buf`. A Close frame is turned into a
+    /// `Request::Close`, everything else is passed on as a `Request::Frame`
+    /// once it has passed every check, or as part of a `Request::Message`
+    /// with `reassemble_messages`.
+    ///
+    /// Once it has failed, every later call fails with the same error: the
+    /// frame that caused it is gone from `buf`, so the state no longer
+    /// matches the stream.
+    pub fn decode(&mut self,
+                  buf: &mut BytesMut,
+                  config: &Config,
+                  extensions: &mut Extensions,
+                  close_state: &mut CloseState)
+                  -> io::Result<Option<Request>> {
+        if let Some(ref e) = self.failed {
+            return Err(e.clone().into());
+        }
+        match self.decode_frame(buf, config, extensions, close_state) {
+            Err(e) => {
+                self.failed = Some(match WebSocketError::from_io(&e) {
+                    Some(ws_error) => ws_error.clone(),
+                    None => WebSocketError::Io(io::Error::new(e.kind(), e.to_string())),
+                });
+                Err(e)
+            }
+            res => res,
+        }
+    }
+
+    fn decode_frame(&mut self,
+                    buf: &mut BytesMut,
+                    config: &Config,
+                    extensions: &mut Extensions,
+                    close_state: &mut CloseState)
+                    -> io::Result<Option<Request>> {
+        loop {
+            let frame = match try!(decode_with_limit(buf, config.get_max_frame_size())) {
+                Some(Request::Frame(frame)) => frame,
+                req => return Ok(req),
+            };
+            if frame.header.is_masked != self.masked {
+                let err = if self.masked {
+                    WebSocketError::UnmaskedClientFrame
+                } else {
+                    WebSocketError::MaskedServerFrame
+                };
+                return Err(err.into());
+            }
+            let message = try!(self.fragmentation.check(&frame));
+            let max_message_size = config.get_max_message_size();
+            let budget = message_budget(&frame, self.message_len, max_message_size);
+            let frame = try!(extensions.decode_frame(frame, budget));
+            try!(check_message_size(&frame, &mut self.message_len, max_message_size));
+            close_state.received(&frame);
+            let opcode = match message {
+                Some(opcode) => opcode,
+                None if frame.header.opcode == Opcode::Close => {
+                    let close = try!(parse_close_payload(&frame.payload));
+                    return Ok(Some(Request::Close(close)));
+                }
+                None => return Ok(Some(Request::Frame(frame))),
+            };
+            try!(check_text(&frame, &opcode, &mut self.text));
+            if self.reassemble && frame.header.opcode != Opcode::Continuation {
+                self.message = Some(Vec::new());
+            }
+            let mut data = match self.message.take() {
+                Some(data) => data,
+                None => return Ok(Some(Request::Frame(frame))),
+            };
+            data.extend_from_slice(&frame.payload);
+            if !frame.header.is_final {
+                self.message = Some(data);
+                continue;
+            }
+            let msg = match opcode {
+                // Safe because the buffer was created by the same frame as
+                // the validator in `check_text`, which has seen every frame
+                // since and finished at the final one
+                Opcode::Text => Message::Text(unsafe { String::from_utf8_unchecked(data) }),
+                _ => Message::Binary(data),
+            };
+            return Ok(Some(Request::Message(msg)));
+        }
+    }
+}
+
+/// The way out for frames the application sends, shared by both codecs:
+/// control frame rules, the close handshake, extensions and
+/// `max_outgoing_frame_size`. The frames to write come back unmasked, a
+/// client masks each of them itself.
+pub fn outgoing_frames(msg: Frame,
+                       config: &Config,
+                       extensions: &mut Extensions,
+                       close_state: &mut CloseState)
+                       -> io::Result<Vec<Frame>> {
+    // Before the close state sees it, an invalid Close must not count
+    try!(msg.header.check_control());
+    try!(close_state.sending(&msg));
+    let mut msg = msg;
+    msg.header.is_masked = false;
+    msg.header.masking_key = 0;
+    let frames = match config.get_max_outgoing_frame_size() {
+        Some(size) => fragment(msg, size),
+        None => vec![msg],
+    };
+    // Extensions see the frames as they go on the wire, like when decoding
+    frames.into_iter().map(|frame| extensions.encode_frame(frame)).collect()
+}
+
+/// How long the payload of `frame` may become once extensions have decoded
+/// it, without its message going over `max_message_size`.
+pub fn message_budget(frame: &Frame, message_len: usize, max_message_size: usize) -> usize {
+    match frame.header.opcode {
+        Opcode::Continuation => max_message_size.saturating_sub(message_len),
+        _ => max_message_size,
+    }
+}
+
+/// Adds an incoming data frame to `message_len`, the length of the message
+/// it belongs to so far, and fails with `PayloadTooLarge` once that is over
+/// `max_message_size`.
+pub fn check_message_size(frame: &Frame,
+                          message_len: &mut usize,
+                          max_message_size: usize)
+                          -> io::Result<()> {
+    match frame.header.opcode {
+        Opcode::Text | Opcode::Binary => *message_len = frame.header.payload_len,
+        Opcode::Continuation => {
+            *message_len = message_len.saturating_add(frame.header.payload_len)
+        }
+        _ => return Ok(()),
+    }
+    if *message_len > max_message_size {
+        return Err(WebSocketError::PayloadTooLarge.into());
+    }
+    Ok(())
+}
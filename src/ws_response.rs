@@ -1,12 +1,16 @@
+use std::io;
+
 use base64;
 use bytes::{BytesMut, BufMut, BigEndian};
 use tokio_minihttp;
 use ring::digest;
-use ws_frame::{Frame, opcode_to_u8};
+use ws_frame::{Frame, fragment, mask_in_place, opcode_to_u8};
 
 #[cfg(test)]
 mod tests {
-    use ws_frame::{Opcode, Header, new_text_frame};
+    use bytes::Bytes;
+
+    use ws_frame::{Opcode, Header, mask_bytes, new_text_frame};
 
     use super::*;
 
@@ -21,12 +25,15 @@ mod tests {
         let frame = Frame {
             header: Header {
                 is_final: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Binary,
                 is_masked: false,
                 payload_len: 0,
                 masking_key: 0,
             },
-            payload: Vec::new(),
+            payload: Bytes::new(),
         };
 
         let expected_data = vec![
@@ -34,7 +41,7 @@ mod tests {
             0x00u8 + 0x00u8, // unmasked empty
         ];
         let mut buf = BytesMut::with_capacity(0);
-        encode(frame, &mut buf);
+        encode(frame, &mut buf).unwrap();
 
         assert_eq!(buf, expected_data);
     }
@@ -44,12 +51,15 @@ mod tests {
         let frame = Frame {
             header: Header {
                 is_final: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Binary,
                 is_masked: false,
                 payload_len: 5,
                 masking_key: 0,
             },
-            payload: vec![1, 2, 3, 4, 5],
+            payload: Bytes::from(vec![1, 2, 3, 4, 5]),
         };
 
         let expected_data = vec![0x80u8 + 0x02u8, // fin bin
@@ -60,7 +70,7 @@ mod tests {
                                  4u8,
                                  5u8];
         let mut buf = BytesMut::with_capacity(0);
-        encode(frame, &mut buf);
+        encode(frame, &mut buf).unwrap();
 
         assert_eq!(buf, expected_data);
     }
@@ -71,12 +81,15 @@ mod tests {
         let frame = Frame {
             header: Header {
                 is_final: false,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Continuation,
                 is_masked: true,
                 payload_len: 256,
                 masking_key: 0x11121314,
             },
-            payload: payload.clone(),
+            payload: Bytes::from(payload.clone()),
         };
 
         let mut expected_data = vec![0x00u8 + 0x00u8, // continuation continuation
@@ -87,10 +100,10 @@ mod tests {
                                      0x12,
                                      0x13,
                                      0x14];
-        expected_data.extend(payload.iter());
+        expected_data.extend(mask_bytes(0x11121314, &payload));
 
         let mut buf = BytesMut::with_capacity(0);
-        encode(frame, &mut buf);
+        encode(frame, &mut buf).unwrap();
 
         assert_eq!(buf, expected_data);
     }
@@ -101,12 +114,15 @@ mod tests {
         let frame = Frame {
             header: Header {
                 is_final: false,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
                 opcode: Opcode::Continuation,
                 is_masked: true,
                 payload_len: 65536,
                 masking_key: 0x11121314,
             },
-            payload: payload.clone(),
+            payload: Bytes::from(payload.clone()),
         };
 
 
@@ -124,10 +140,10 @@ mod tests {
                                      0x12,
                                      0x13,
                                      0x14];
-        expected_data.extend(payload.iter());
+        expected_data.extend(mask_bytes(0x11121314, &payload));
 
         let mut buf = BytesMut::with_capacity(0);
-        encode(frame, &mut buf);
+        encode(frame, &mut buf).unwrap();
 
         assert_eq!(buf, expected_data);
     }
@@ -142,7 +158,7 @@ mod tests {
         expected_data.extend(text.as_bytes());
         let ttf = new_text_frame(text, None);
         let mut buf = BytesMut::with_capacity(0);
-        encode(ttf, &mut buf);
+        encode(ttf, &mut buf).unwrap();
         assert_eq!(buf, expected_data);
     }
 
@@ -156,11 +172,105 @@ mod tests {
         let ttf = new_text_frame(text, Some(0x11121314));
         assert_eq!(ttf.clone().payload_string().unwrap(), text);
         let mut buf = BytesMut::with_capacity(128);
-        encode(ttf, &mut buf);
+        encode(ttf, &mut buf).unwrap();
 
         assert_eq!(buf[0], expected_start[0]);
         assert_eq!(buf[1], expected_start[1]);
     }
+
+    #[test]
+    fn fin_rsv1_text() {
+        let mut frame = new_text_frame("", None);
+        frame.header.rsv1 = true;
+        let mut buf = BytesMut::with_capacity(0);
+        encode(frame, &mut buf).unwrap();
+
+        assert_eq!(buf, vec![0xc1u8, 0x00u8]);
+    }
+
+    #[test]
+    fn fin_rsv2_rsv3_text() {
+        let mut frame = new_text_frame("", None);
+        frame.header.rsv2 = true;
+        frame.header.rsv3 = true;
+        let mut buf = BytesMut::with_capacity(0);
+        encode(frame, &mut buf).unwrap();
+
+        assert_eq!(buf, vec![0xb1u8, 0x00u8]);
+    }
+
+    #[test]
+    fn fragmented_text_frame() {
+        let expected_data = vec![0x01u8, // text
+                                 0x02u8, // unmasked 2 long
+                                 b'b',
+                                 b'l',
+                                 0x00u8, // continuation
+                                 0x02u8,
+                                 b'u',
+                                 b'b',
+                                 0x80u8, // fin continuation
+                                 0x01u8,
+                                 b'!'];
+        let mut buf = BytesMut::with_capacity(0);
+        encode_fragmented(new_text_frame("blub!", None), 2, &mut buf).unwrap();
+
+        assert_eq!(buf, expected_data);
+    }
+
+    #[test]
+    fn fragmented_masked_text_frame() {
+        // The key must not be reused for every fragment
+        let mut buf = BytesMut::with_capacity(0);
+        match encode_fragmented(new_text_frame("blub!", Some(0x11121314)), 4, &mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        assert!(buf.is_empty());
+
+        // A masked frame that fits is written with its key, like `encode` does
+        encode_fragmented(new_text_frame("blub", Some(0x11121314)), 4, &mut buf).unwrap();
+        assert_eq!(&buf[..6], &[0x81u8, 0x84u8, 0x11, 0x12, 0x13, 0x14][..]);
+    }
+
+    #[test]
+    fn small_frame_not_fragmented() {
+        let mut buf = BytesMut::with_capacity(0);
+        encode_fragmented(new_text_frame("blub", None), 4, &mut buf).unwrap();
+
+        assert_eq!(buf, vec![0x81u8, 0x04u8, b'b', b'l', b'u', b'b']);
+    }
+
+    #[test]
+    fn zero_frame_size() {
+        let mut buf = BytesMut::with_capacity(0);
+        match encode_fragmented(new_text_frame("blub", None), 0, &mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn control_frame_not_fragmented() {
+        let frame = Frame {
+            header: Header {
+                is_final: true,
+                rsv1: false,
+                rsv2: false,
+                rsv3: false,
+                opcode: Opcode::Ping,
+                is_masked: false,
+                payload_len: 5,
+                masking_key: 0,
+            },
+            payload: Bytes::from(vec![1, 2, 3, 4, 5]),
+        };
+        let mut buf = BytesMut::with_capacity(0);
+        encode_fragmented(frame, 2, &mut buf).unwrap();
+
+        assert_eq!(buf, vec![0x89u8, 0x05u8, 1, 2, 3, 4, 5]);
+    }
 }
 
 pub type Response = Frame;
@@ -178,31 +288,63 @@ fn response_len(msg: &Response) -> usize {
     }
     len
 }
-pub fn encode(msg: Response, buf: &mut BytesMut) {
+/// Writes `msg` to `buf`, masking the payload if `msg.header.is_masked` is
+/// set. Fails without writing anything if `msg` is a control frame that
+/// breaks RFC 6455 §5.5.
+pub fn encode(msg: Response, buf: &mut BytesMut) -> io::Result<()> {
+    try!(msg.header.check_control());
     buf.reserve(response_len(&msg));
-    buf.put(0u8);
-    buf.put(0u8);
+    let mut first = msg.header.rsv_bits();
     if msg.header.is_final {
-        buf[0] |= 0x80;
-    }
-    let op_u8 = opcode_to_u8(msg.header.opcode);
-    buf[0] |= op_u8;
-    if msg.header.is_masked {
-        buf[1] |= 0x80;
+        first |= 0x80;
     }
+    first |= opcode_to_u8(msg.header.opcode);
+    buf.put(first);
+    let mask_bit = if msg.header.is_masked { 0x80 } else { 0 };
     if msg.header.payload_len < 126 {
-        buf[1] |= msg.header.payload_len as u8;
+        buf.put(mask_bit | msg.header.payload_len as u8);
     } else if msg.header.payload_len < 65536 {
-        buf[1] |= 0x7e;
+        buf.put(mask_bit | 0x7e);
         buf.put_u16::<BigEndian>(msg.header.payload_len as u16);
     } else {
-        buf[1] |= 0x7f;
+        buf.put(mask_bit | 0x7f);
         buf.put_u64::<BigEndian>(msg.header.payload_len as u64);
     }
     if msg.header.is_masked {
         buf.put_u32::<BigEndian>(msg.header.masking_key);
     }
-    buf.put_slice(msg.payload.as_slice());
+    let start = buf.len();
+    buf.put_slice(&msg.payload);
+    if msg.header.is_masked {
+        mask_in_place(msg.header.masking_key, &mut buf[start..]);
+    }
+    Ok(())
+}
+
+/// Like `encode`, but splits payloads longer than `max_frame_size` across
+/// several frames.
+///
+/// Fails with `InvalidInput` if `max_frame_size` is 0, or if `msg` is masked
+/// and would have to be split: every fragment needs a key of its own, which
+/// `WebSocketClientCodec` picks for each of them.
+pub fn encode_fragmented(msg: Response,
+                         max_frame_size: usize,
+                         buf: &mut BytesMut)
+                         -> io::Result<()> {
+    if max_frame_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame size must be positive"));
+    }
+    if msg.header.is_control() || msg.payload.len() <= max_frame_size {
+        return encode(msg, buf);
+    }
+    if msg.header.is_masked {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "masked frames can't be fragmented"));
+    }
+    for frame in fragment(msg, max_frame_size) {
+        try!(encode(frame, buf));
+    }
+    Ok(())
 }
 
 fn hash_sha1(input: &str) -> digest::Digest {
@@ -211,7 +353,7 @@ fn hash_sha1(input: &str) -> digest::Digest {
     ctx.finish()
 }
 
-fn hash_key(b64_key: &str) -> String {
+pub fn hash_key(b64_key: &str) -> String {
     let mut input = b64_key.to_string();
     input.push_str("258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
     let sha_input = hash_sha1(&input);
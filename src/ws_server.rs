@@ -0,0 +1,291 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::future::{self, Loop};
+use futures::sync::mpsc;
+use tokio_io::codec::Framed;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use ws_config::Config;
+use ws_error::WebSocketError;
+use ws_frame::new_text_frame;
+use ws_handshake::Handshake;
+use ws_request::Request;
+use ws_response::Response;
+use ws_transport::send_pending;
+use WebSocketCodec;
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use ws_frame::{Opcode, new_ping_frame};
+    use ws_request;
+    use tests::{MockTransport, mock_io};
+
+    use super::*;
+
+    const UPGRADE: &'static str = "GET /chat HTTP/1.1\r\n\
+                                   Host: server.example.com\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                   Sec-WebSocket-Version: 13\r\n\r\n";
+
+    #[test]
+    fn accept_writes_101() {
+        let (io, output) = mock_io(UPGRADE.as_bytes());
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let (handshake, transport) = accept_with_config(io, Config::new(), Some(addr))
+            .wait()
+            .unwrap();
+        assert_eq!(handshake.path(), "/chat");
+        assert_eq!(handshake.peer_addr, Some(addr));
+        let res = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 101"));
+        assert!(res.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // Nothing but the response was written
+        let mut transport = transport;
+        output.borrow_mut().clear();
+        transport.start_send(new_text_frame("blub", None)).unwrap();
+        transport.poll_complete().unwrap();
+        assert_eq!(&output.borrow()[..], &[0x81, 0x04, b'b', b'l', b'u', b'b'][..]);
+    }
+
+    #[test]
+    fn accept_rejected() {
+        let (io, output) = mock_io(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\n\r\n");
+        assert!(accept(io).wait().is_err());
+        let res = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 400"));
+    }
+
+    fn mock_connection(incoming: Vec<Request>) -> Connection<MockTransport> {
+        let mut transport = MockTransport::idle(false);
+        transport.incoming.extend(incoming);
+        Connection::new(transport)
+    }
+
+    #[test]
+    fn push_without_request() {
+        let mut conn = mock_connection(Vec::new());
+        let sender = conn.sender();
+        sender.push(new_text_frame("one", None)).unwrap();
+        sender.clone().push(new_text_frame("two", None)).unwrap();
+        let poll = future::poll_fn(|| Ok::<_, ()>(Async::Ready(conn.poll())));
+        assert!(poll.wait().unwrap().unwrap().is_not_ready());
+
+        let sent: Vec<String> = conn.get_ref()
+            .outgoing
+            .iter()
+            .map(|frame| frame.payload_string().unwrap())
+            .collect();
+        assert_eq!(sent, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn requests_need_no_reply() {
+        let mut buf = BytesMut::from(vec![0x89, 0x00, 0x81, 0x01, b'x']);
+        let mut incoming = Vec::new();
+        while let Some(req) = ws_request::decode(&mut buf).unwrap() {
+            incoming.push(req);
+        }
+        let conn = mock_connection(incoming);
+        let reqs = conn.take(2).collect().wait().unwrap();
+        assert_eq!(reqs.len(), 2);
+        match reqs[1] {
+            Request::Frame(ref frame) => assert_eq!(frame.header.opcode, Opcode::Text),
+            ref e => panic!("unexpected request: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn push_wakes_connection() {
+        let mut conn = mock_connection(Vec::new());
+        let sender = conn.sender();
+        let woken = future::poll_fn(move || {
+            match try!(conn.poll()) {
+                Async::NotReady if conn.get_ref().outgoing.is_empty() => {
+                    // Pushing from elsewhere has to get the connection polled
+                    // again, or this never finishes
+                    sender.push(new_ping_frame(b"", None)).unwrap();
+                    Ok::<_, io::Error>(Async::NotReady)
+                }
+                Async::NotReady => Ok(Async::Ready(conn.get_ref().outgoing.len())),
+                Async::Ready(_) => panic!("connection ended"),
+            }
+        });
+        assert_eq!(woken.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn send_after_drop() {
+        let conn = mock_connection(Vec::new());
+        let sender = conn.sender();
+        drop(conn);
+        assert!(sender.push(new_text_frame("blub", None)).is_err());
+    }
+}
+
+/// Performs the server side of the opening handshake over `io`, resolving
+/// to the client's request and a framed transport once the 101 response is
+/// written.
+///
+/// Plain HTTP requests are answered by the `Config::http_handler` while
+/// waiting for the upgrade. A rejected handshake is answered with its error
+/// response, after which the future fails.
+///
+/// The transport passes every frame on, Pings included, and ignores
+/// `Config::pong_events`: wrap it in a `PingPong` to have Pings answered,
+/// with a `KeepAlive` in between if idle peers should be pinged.
+pub fn accept<T>(io: T) -> Box<Future<Item = (Handshake, Framed<T, WebSocketCodec>), Error = io::Error>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    accept_with_config(io, Config::new(), None)
+}
+
+/// Like `accept`, with `config` and the address of the peer, if known, for
+/// `Handshake::peer_addr`.
+pub fn accept_with_config<T>
+    (io: T,
+     config: Config,
+     peer_addr: Option<SocketAddr>)
+     -> Box<Future<Item = (Handshake, Framed<T, WebSocketCodec>), Error = io::Error>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let mut codec = WebSocketCodec::with_config(config);
+    if let Some(addr) = peer_addr {
+        codec.peer_addr(addr);
+    }
+    let transport = io.framed(codec);
+    Box::new(future::loop_fn(transport, |transport| {
+        transport.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(req, transport)| -> Box<Future<Item = _, Error = _>> {
+                // The codec writes the HTTP response in place of the next
+                // frame sent, whatever that frame is
+                let placeholder = new_text_frame("", None);
+                match req {
+                    Some(Request::Open(handshake)) => {
+                        Box::new(transport.send(placeholder)
+                            .map(move |transport| Loop::Break((handshake, transport))))
+                    }
+                    Some(Request::Http(_)) => Box::new(transport.send(placeholder).map(Loop::Continue)),
+                    Some(Request::Rejected(_)) => {
+                        Box::new(transport.send(placeholder).and_then(|_| {
+                            let reason = "handshake rejected".to_string();
+                            Err(WebSocketError::HandshakeFailed(reason).into())
+                        }))
+                    }
+                    _ => {
+                        let reason = "connection closed during handshake".to_string();
+                        Box::new(future::err(WebSocketError::HandshakeFailed(reason).into()))
+                    }
+                }
+            })
+    }))
+}
+
+/// A cloneable handle that queues frames on a `Connection`. Frames are
+/// written as the connection is polled, in the order they were queued from
+/// all handles.
+#[derive(Clone)]
+pub struct Sender {
+    tx: mpsc::UnboundedSender<Response>,
+}
+
+impl Sender {
+    /// Queues `frame`, failing once the `Connection` has been dropped.
+    pub fn push(&self, frame: Response) -> io::Result<()> {
+        self.tx
+            .unbounded_send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+    }
+}
+
+impl Sink for Sender {
+    type SinkItem = Response;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Response) -> StartSend<Response, io::Error> {
+        try!(self.push(item));
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Wraps a server transport, such as the one from `accept`, so that reading
+/// and writing are independent: requests are read from the `Stream` without
+/// having to answer them, and frames are pushed at any time through the
+/// `Sender` handles from `sender`.
+///
+/// Queued frames are written while the stream is polled, so keep polling it
+/// for as long as the connection should stay up.
+pub struct Connection<T> {
+    inner: T,
+    outgoing: mpsc::UnboundedReceiver<Response>,
+    sender: Sender,
+    pending: Option<Response>,
+}
+
+impl<T> Connection<T> {
+    pub fn new(inner: T) -> Connection<T> {
+        let (tx, rx) = mpsc::unbounded();
+        Connection {
+            inner: inner,
+            outgoing: rx,
+            sender: Sender { tx: tx },
+            pending: None,
+        }
+    }
+
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Connection<T>
+    where T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    fn flush_outgoing(&mut self) -> Poll<(), io::Error> {
+        while try!(send_pending(&mut self.inner, &mut self.pending)).is_ready() {
+            match self.outgoing.poll() {
+                Ok(Async::Ready(Some(frame))) => self.pending = Some(frame),
+                // The connection holds a sender itself, so the queue never
+                // ends
+                _ => break,
+            }
+        }
+        self.inner.poll_complete()
+    }
+}
+
+impl<T> Stream for Connection<T>
+    where T: Stream<Item = Request, Error = io::Error>,
+          T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        try!(self.flush_outgoing());
+        self.inner.poll()
+    }
+}
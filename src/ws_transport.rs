@@ -0,0 +1,414 @@
+use std::io;
+use std::time::Duration;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+use ws_close::CloseCode;
+use ws_frame::{Opcode, new_close_frame, new_ping_frame, new_pong_frame};
+use ws_request::Request;
+use ws_response::Response;
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Either};
+    use tokio_core::reactor::Core;
+
+    use ws_frame::new_text_frame;
+    use ws_handshake::Handshake;
+    use tests::MockTransport;
+
+    use super::*;
+
+    fn run(mut transport: PingPong<MockTransport>) -> (Vec<Request>, Vec<Response>) {
+        let mut reqs = Vec::new();
+        while let Ok(Async::Ready(Some(req))) = transport.poll() {
+            reqs.push(req);
+        }
+        (reqs, transport.into_inner().outgoing)
+    }
+
+    #[test]
+    fn ping_answered_with_pong() {
+        let transport = PingPong::new(MockTransport::new(vec![
+            new_ping_frame(b"ping", Some(0x11121314)),
+            new_text_frame("blub", None),
+        ]));
+        let (reqs, sent) = run(transport);
+
+        assert_eq!(reqs.len(), 1);
+        match reqs[0] {
+            Request::Frame(ref frame) => assert_eq!(frame.header.opcode, Opcode::Text),
+            ref e => panic!("unexpected request: {:?}", e),
+        }
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].header.opcode, Opcode::Pong);
+        assert!(!sent[0].header.is_masked);
+        assert_eq!(sent[0].payload, b"ping".to_vec());
+    }
+
+    #[test]
+    fn pong_events() {
+        let transport = PingPong::new(MockTransport::new(vec![new_pong_frame(b"", None)]));
+        let (reqs, sent) = run(transport);
+        assert!(reqs.is_empty());
+        assert!(sent.is_empty());
+
+        let mut transport = PingPong::new(MockTransport::new(vec![new_pong_frame(b"", None)]));
+        transport.pong_events(true);
+        let (reqs, sent) = run(transport);
+        assert_eq!(reqs.len(), 1);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn ping_flood_keeps_latest_pong() {
+        let mut mock = MockTransport::new(vec![
+            new_ping_frame(b"1", None),
+            new_ping_frame(b"2", None),
+            new_ping_frame(b"3", None),
+        ]);
+        mock.full = true;
+        let mut transport = PingPong::new(mock);
+        assert!(match transport.poll() {
+            Ok(Async::Ready(None)) => true,
+            _ => false,
+        });
+        assert!(transport.get_ref().outgoing.is_empty());
+
+        transport.get_mut().full = false;
+        let (_, sent) = run(transport);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].payload, b"3".to_vec());
+    }
+
+    /// Polls `transport` for `ms` milliseconds, or until it fails or ends.
+    fn run_for(core: &mut Core, transport: &mut KeepAlive<MockTransport>, ms: u64) -> io::Result<()> {
+        let timeout = Timeout::new(Duration::from_millis(ms), &core.handle()).unwrap();
+        let reqs = future::poll_fn(|| {
+            while try_ready!(transport.poll()).is_some() {}
+            Ok(Async::Ready(()))
+        });
+        match core.run(reqs.select2(timeout)) {
+            Ok(_) => Ok(()),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => panic!("timer failed: {:?}", e),
+        }
+    }
+
+    fn assert_timed_out(res: io::Result<()>, sent: &[Response]) {
+        match res {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            e => panic!("expected timeout: {:?}", e),
+        }
+        // The peer is told why, with 1001 Going Away
+        let close = sent.last().unwrap();
+        assert_eq!(close.header.opcode, Opcode::Close);
+        assert_eq!(&close.payload[..2], &[0x03, 0xe9][..]);
+    }
+
+    #[test]
+    fn keepalive_dead_peer() {
+        let mut core = Core::new().unwrap();
+        let mut transport = KeepAlive::new(MockTransport::idle(false),
+                                           Duration::from_millis(10),
+                                           Duration::from_millis(20),
+                                           &core.handle())
+            .unwrap();
+        let res = run_for(&mut core, &mut transport, 1000);
+        let sent = &transport.get_ref().outgoing;
+        assert_eq!(sent[0].header.opcode, Opcode::Ping);
+        assert_timed_out(res, sent);
+    }
+
+    #[test]
+    fn keepalive_waits_for_handshake() {
+        let mut core = Core::new().unwrap();
+        let mut mock = MockTransport::idle(false);
+        mock.incoming.push_back(Request::Open(Handshake {
+            method: "GET".to_string(),
+            uri: "/".to_string(),
+            headers: Vec::new(),
+            peer_addr: None,
+            protocol: None,
+        }));
+        let mut transport = KeepAlive::new(mock,
+                                           Duration::from_millis(10),
+                                           Duration::from_millis(20),
+                                           &core.handle())
+            .unwrap();
+        transport.wait_for_handshake();
+        run_for(&mut core, &mut transport, 50).unwrap();
+        assert!(transport.get_ref().outgoing.is_empty());
+
+        // The answer to the Open becomes the 101 response, pings follow it
+        transport.start_send(new_text_frame("", None)).unwrap();
+        let res = run_for(&mut core, &mut transport, 1000);
+        let sent = &transport.get_ref().outgoing;
+        assert_eq!(sent[0].header.opcode, Opcode::Text);
+        assert_eq!(sent[1].header.opcode, Opcode::Ping);
+        assert_timed_out(res, sent);
+    }
+
+    #[test]
+    fn keepalive_live_peer() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let transport = KeepAlive::new(MockTransport::idle(true),
+                                       Duration::from_millis(10),
+                                       Duration::from_millis(20),
+                                       &handle)
+            .unwrap();
+        let pongs = transport.fold(0, |pongs, _| Ok::<_, io::Error>(pongs + 1));
+        let timeout = Timeout::new(Duration::from_millis(100), &handle).unwrap();
+        match core.run(pongs.select2(timeout)) {
+            Ok(Either::B(_)) => {}
+            Ok(Either::A(_)) => panic!("stream ended"),
+            Err(Either::A((e, _))) => panic!("transport failed: {:?}", e),
+            Err(Either::B((e, _))) => panic!("timer failed: {:?}", e),
+        }
+    }
+}
+
+/// Hands `pending` to `sink`, keeping it for later if the sink is full.
+pub fn send_pending<S>(sink: &mut S, pending: &mut Option<Response>) -> Poll<(), io::Error>
+    where S: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    if let Some(frame) = pending.take() {
+        if let AsyncSink::NotReady(frame) = try!(sink.start_send(frame)) {
+            *pending = Some(frame);
+            return Ok(Async::NotReady);
+        }
+    }
+    Ok(Async::Ready(()))
+}
+
+/// Wraps a WebSocket transport and answers Pings with a Pong carrying the
+/// same payload. Pings are not passed on to the application, Pongs only if
+/// `pong_events(true)` was set.
+///
+/// While the socket takes no more frames, only the Pong for the latest Ping
+/// is kept, as RFC 6455 §5.5.3 allows, so a peer that sends Pings without
+/// reading can't make the queue grow.
+pub struct PingPong<T> {
+    inner: T,
+    pending: Option<Response>,
+    pong_events: bool,
+}
+
+impl<T> PingPong<T> {
+    pub fn new(inner: T) -> PingPong<T> {
+        PingPong {
+            inner: inner,
+            pending: None,
+            pong_events: false,
+        }
+    }
+
+    pub fn pong_events(&mut self, enabled: bool) -> &mut PingPong<T> {
+        self.pong_events = enabled;
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Stream for PingPong<T>
+    where T: Stream<Item = Request, Error = io::Error>,
+          T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        loop {
+            if try!(send_pending(&mut self.inner, &mut self.pending)).is_ready() {
+                try!(self.inner.poll_complete());
+            }
+            let frame = match try_ready!(self.inner.poll()) {
+                Some(Request::Frame(frame)) => frame,
+                req => return Ok(Async::Ready(req)),
+            };
+            match frame.header.opcode {
+                Opcode::Ping => {
+                    // Replaces a Pong that hasn't been sent yet
+                    self.pending = Some(new_pong_frame(frame.unmasked_payload(), None));
+                }
+                Opcode::Pong if !self.pong_events => {}
+                _ => return Ok(Async::Ready(Some(Request::Frame(frame)))),
+            }
+        }
+    }
+}
+
+impl<T> Sink for PingPong<T>
+    where T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    type SinkItem = Response;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Response) -> StartSend<Response, io::Error> {
+        if try!(send_pending(&mut self.inner, &mut self.pending)).is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(send_pending(&mut self.inner, &mut self.pending));
+        self.inner.poll_complete()
+    }
+}
+
+/// Wraps a WebSocket transport and sends a Ping every `interval`. If no Pong
+/// arrives within `timeout` of a Ping, a Close with 1001 Going Away is sent
+/// if the socket takes it and the stream fails with `TimedOut`.
+///
+/// Pongs are passed through, so this has to sit below a `PingPong` that
+/// hides them.
+///
+/// The timers need a `Handle`, so this can't be turned on through `Config`.
+/// Wrap the transport from `accept`, which has finished the handshake, or
+/// call `wait_for_handshake` when wrapping a codec that hasn't, since a Ping
+/// sent before the 101 response would be taken for the answer to the
+/// handshake.
+pub struct KeepAlive<T> {
+    inner: T,
+    interval: Interval,
+    timeout: Duration,
+    handle: Handle,
+    deadline: Option<Timeout>,
+    pending: Option<Response>,
+    handshake: HandshakeState,
+}
+
+#[derive(Debug, PartialEq)]
+enum HandshakeState {
+    // No `Request::Open` yet
+    Waiting,
+    // The next frame sent answers the `Request::Open`
+    Answering,
+    Done,
+}
+
+impl<T> KeepAlive<T> {
+    pub fn new(inner: T,
+               interval: Duration,
+               timeout: Duration,
+               handle: &Handle)
+               -> io::Result<KeepAlive<T>> {
+        Ok(KeepAlive {
+            inner: inner,
+            interval: try!(Interval::new(interval, handle)),
+            timeout: timeout,
+            handle: handle.clone(),
+            deadline: None,
+            pending: None,
+            handshake: HandshakeState::Done,
+        })
+    }
+
+    /// Holds pings until a `Request::Open` has been read and answered.
+    pub fn wait_for_handshake(&mut self) -> &mut KeepAlive<T> {
+        self.handshake = HandshakeState::Waiting;
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> KeepAlive<T>
+    where T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    fn poll_timers(&mut self) -> io::Result<()> {
+        while let Async::Ready(Some(())) = try!(self.interval.poll()) {
+            // Only one Ping is outstanding at a time
+            if self.deadline.is_none() && self.handshake == HandshakeState::Done {
+                self.pending = Some(new_ping_frame(b"", None));
+                self.deadline = Some(try!(Timeout::new(self.timeout, &self.handle)));
+            }
+        }
+        let expired = match self.deadline {
+            Some(ref mut deadline) => try!(deadline.poll()).is_ready(),
+            None => false,
+        };
+        if expired {
+            // Best effort, the connection fails either way
+            let close = new_close_frame(CloseCode::GoingAway, "keepalive timeout", None);
+            if let Ok(AsyncSink::Ready) = self.inner.start_send(close) {
+                let _ = self.inner.poll_complete();
+            }
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "keepalive timeout"));
+        }
+        Ok(())
+    }
+}
+
+impl<T> Stream for KeepAlive<T>
+    where T: Stream<Item = Request, Error = io::Error>,
+          T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        try!(self.poll_timers());
+        if try!(send_pending(&mut self.inner, &mut self.pending)).is_ready() {
+            try!(self.inner.poll_complete());
+        }
+        let req = try_ready!(self.inner.poll());
+        match req {
+            Some(Request::Frame(ref frame)) if frame.header.opcode == Opcode::Pong => {
+                self.deadline = None;
+            }
+            Some(Request::Open(_)) if self.handshake == HandshakeState::Waiting => {
+                self.handshake = HandshakeState::Answering;
+            }
+            _ => {}
+        }
+        Ok(Async::Ready(req))
+    }
+}
+
+impl<T> Sink for KeepAlive<T>
+    where T: Sink<SinkItem = Response, SinkError = io::Error>
+{
+    type SinkItem = Response;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Response) -> StartSend<Response, io::Error> {
+        if try!(send_pending(&mut self.inner, &mut self.pending)).is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let res = try!(self.inner.start_send(item));
+        if res.is_ready() && self.handshake == HandshakeState::Answering {
+            self.handshake = HandshakeState::Done;
+        }
+        Ok(res)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(send_pending(&mut self.inner, &mut self.pending));
+        self.inner.poll_complete()
+    }
+}
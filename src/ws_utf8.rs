@@ -0,0 +1,176 @@
+use std::io;
+use std::str;
+
+use ws_error::WebSocketError;
+use ws_frame::{Frame, Opcode};
+
+#[cfg(test)]
+mod tests {
+    use ws_frame::new_text_frame;
+
+    use super::*;
+
+    fn validate_split(data: &[u8], at: usize) -> Result<(), WebSocketError> {
+        let mut validator = Utf8Validator::new();
+        try!(validator.feed(&data[..at]));
+        try!(validator.feed(&data[at..]));
+        validator.finish()
+    }
+
+    #[test]
+    fn valid_at_every_split() {
+        let text = "aé€😀z".as_bytes();
+        for at in 0..text.len() + 1 {
+            assert!(validate_split(text, at).is_ok(), "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut validator = Utf8Validator::new();
+        for b in "€😀".as_bytes() {
+            validator.feed(&[*b]).unwrap();
+        }
+        validator.finish().unwrap();
+    }
+
+    #[test]
+    fn invalid_fails_fast() {
+        // An invalid continuation byte is reported before the message ends
+        let mut validator = Utf8Validator::new();
+        validator.feed(&[0xe2, 0x82]).unwrap();
+        assert!(validator.feed(&[0x28]).is_err());
+
+        // Surrogates and code points above U+10FFFF are caught on their
+        // second byte
+        assert!(Utf8Validator::new().feed(&[0xed, 0xa0]).is_err());
+        assert!(Utf8Validator::new().feed(&[0xf4, 0x90]).is_err());
+        assert!(Utf8Validator::new().feed(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn truncated_at_end() {
+        let mut validator = Utf8Validator::new();
+        validator.feed(&[b'a', 0xf0, 0x9f]).unwrap();
+        match validator.finish() {
+            Err(WebSocketError::InvalidUtf8) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn text_across_frames() {
+        let mut text = None;
+        let mut first = new_text_frame("", None);
+        first.header.is_final = false;
+        first.payload = vec![0xe2, 0x82].into();
+        let mut rest = new_text_frame("", None);
+        rest.header.opcode = Opcode::Continuation;
+        rest.payload = vec![0xac].into();
+        check_text(&first, &Opcode::Text, &mut text).unwrap();
+        assert!(text.is_some());
+        check_text(&rest, &Opcode::Text, &mut text).unwrap();
+        assert!(text.is_none());
+
+        // The last frame of a message must not end inside a character
+        check_text(&first, &Opcode::Text, &mut text).unwrap();
+        rest.payload = vec![0xe2].into();
+        assert!(check_text(&rest, &Opcode::Text, &mut text).is_err());
+
+        // Binary continuations are not looked at
+        rest.payload = vec![0xff].into();
+        assert!(check_text(&rest, &Opcode::Binary, &mut None).is_ok());
+    }
+
+    #[test]
+    fn text_continuation_without_start() {
+        let mut rest = new_text_frame("", None);
+        rest.header.opcode = Opcode::Continuation;
+        rest.payload = vec![b'a'].into();
+        match check_text(&rest, &Opcode::Text, &mut None) {
+            Err(ref e) if WebSocketError::from_io(e).is_some() => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+}
+
+/// Validates UTF-8 that arrives in pieces, RFC 6455 §8.1. A character may be
+/// split between pieces, but invalid input is reported as soon as the byte
+/// that makes it invalid is fed.
+#[derive(Debug, Clone)]
+pub struct Utf8Validator {
+    /// The start of a character that was cut off at the end of the last piece.
+    incomplete: [u8; 4],
+    incomplete_len: usize,
+}
+
+impl Utf8Validator {
+    pub fn new() -> Utf8Validator {
+        Utf8Validator {
+            incomplete: [0; 4],
+            incomplete_len: 0,
+        }
+    }
+
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Result<(), WebSocketError> {
+        // Finish the character left over from the last piece first
+        while self.incomplete_len > 0 {
+            let b = match bytes.first() {
+                Some(&b) => b,
+                None => return Ok(()),
+            };
+            self.incomplete[self.incomplete_len] = b;
+            self.incomplete_len += 1;
+            bytes = &bytes[1..];
+            match str::from_utf8(&self.incomplete[..self.incomplete_len]) {
+                Ok(_) => self.incomplete_len = 0,
+                Err(ref e) if e.error_len().is_some() => return Err(WebSocketError::InvalidUtf8),
+                Err(_) => {}
+            }
+        }
+
+        match str::from_utf8(bytes) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.error_len().is_some() => Err(WebSocketError::InvalidUtf8),
+            Err(e) => {
+                let rest = &bytes[e.valid_up_to()..];
+                self.incomplete[..rest.len()].copy_from_slice(rest);
+                self.incomplete_len = rest.len();
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that the input did not end in the middle of a character.
+    pub fn finish(&self) -> Result<(), WebSocketError> {
+        if self.incomplete_len > 0 {
+            return Err(WebSocketError::InvalidUtf8);
+        }
+        Ok(())
+    }
+}
+
+/// Validates the payload of an incoming data frame. `message` is the opcode
+/// of the message the frame belongs to, and `text` holds the state of the
+/// text message in progress, if any.
+pub fn check_text(frame: &Frame, message: &Opcode, text: &mut Option<Utf8Validator>) -> io::Result<()> {
+    if *message != Opcode::Text {
+        return Ok(());
+    }
+    if frame.header.opcode == Opcode::Text {
+        *text = Some(Utf8Validator::new());
+    }
+    match *text {
+        Some(ref mut validator) => {
+            try!(validator.feed(&frame.payload));
+            if !frame.header.is_final {
+                return Ok(());
+            }
+            try!(validator.finish());
+        }
+        // Text whose start was never validated can't be vouched for
+        None => return Err(WebSocketError::InvalidUtf8.into()),
+    }
+    *text = None;
+    Ok(())
+}